@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use ahash::{AHashMap, AHashSet};
+use anyhow::Result;
+
+use crate::output::{base_change_label, multiple_base_changes};
+use crate::position::Position;
+use crate::region::read_bed;
+use crate::ARGS;
+
+const HEADER: &str = "ref\tstart\tend\tsites\tconvertedBaseCount\tunconvertedBaseCount\tmeanSiteRate\tweightedRate";
+
+/// the location and strand of a site
+type SiteKey = (isize, Option<u8>);
+
+/// the written positions of a region, for one `--base-change`. the samples
+/// of a site are pooled: a site counts once, at the rate of its summed counts.
+#[derive(Default, Clone)]
+struct Totals {
+    sites: u64,
+    converted: u64,
+    unconverted: u64,
+    /// sum of the converted fraction of each site
+    rate_sum: f64,
+    /// (location, strand) and [converted, unconverted] counts of the last
+    /// site, until a position of another site comes
+    site: Option<(SiteKey, [u64; 2])>,
+}
+
+impl Totals {
+    fn add(&mut self, p: &Position) {
+        let converted = u64::from(p.converted.count);
+        let unconverted = u64::from(p.unconverted.count);
+        self.converted += converted;
+        self.unconverted += unconverted;
+        let key = (p.location, p.strand);
+        match &mut self.site {
+            Some((site, counts)) if *site == key => {
+                counts[0] += converted;
+                counts[1] += unconverted;
+            }
+            _ => {
+                self.close_site();
+                self.site = Some((key, [converted, unconverted]));
+            }
+        }
+    }
+
+    fn close_site(&mut self) {
+        if let Some((_, [converted, unconverted])) = self.site.take() && converted + unconverted > 0 {
+            self.sites += 1;
+            self.rate_sum += converted as f64 / (converted + unconverted) as f64;
+        }
+    }
+}
+
+enum Mode {
+    /// fixed windows of this many bases
+    Window(usize),
+    /// BED intervals (1-based, half-open) per dna, sorted by start, and the
+    /// dnas in BED order
+    Bed(AHashMap<Vec<u8>, Vec<Range<usize>>>, Vec<Vec<u8>>),
+}
+
+/// sums the positions of the table over `--aggregate-bed` intervals or
+/// `--window`s. positions come sorted per dna, so regions are written as soon
+/// as the positions pass their end, in order of their start.
+pub struct Aggregator {
+    output: BufWriter<File>,
+    mode: Mode,
+    /// the length of a dna of the reference
+    dna_len: fn(&[u8]) -> Option<usize>,
+    dna: Vec<u8>,
+    done: AHashSet<Vec<u8>>,
+    /// BED: the next interval of `dna` to open
+    next: usize,
+    /// the open regions, by start
+    open: VecDeque<(Range<usize>, Vec<Totals>)>,
+}
+
+impl Aggregator {
+    pub fn new(path: &Path, bed: Option<&Path>, window: Option<usize>, dna_len: fn(&[u8]) -> Option<usize>) -> Result<Self> {
+        let mode = match (bed, window) {
+            (Some(bed), _) => {
+                let mut intervals: AHashMap<Vec<u8>, Vec<Range<usize>>> = AHashMap::new();
+                let mut order = Vec::new();
+                for region in read_bed(bed)? {
+                    if !intervals.contains_key(&region.dna) {
+                        order.push(region.dna.clone());
+                    }
+                    intervals.entry(region.dna).or_default().push(region.range);
+                }
+                for ranges in intervals.values_mut() {
+                    ranges.sort_by_key(|r| (r.start, r.end));
+                }
+                Mode::Bed(intervals, order)
+            }
+            (None, Some(window)) => Mode::Window(window),
+            (None, None) => anyhow::bail!("--aggregate-output requires --aggregate-bed or --window"),
+        };
+        let mut output = BufWriter::new(File::create(path)?);
+        write!(output, "{}", HEADER)?;
+        if multiple_base_changes() {
+            write!(output, "\tbaseChange")?;
+        }
+        writeln!(output)?;
+        Ok(Self { output, mode, dna_len, dna: Vec::new(), done: AHashSet::new(), next: 0, open: VecDeque::new() })
+    }
+
+    /// adds a position written to the table
+    pub fn add(&mut self, p: &Position) -> Result<()> {
+        if p.dna != self.dna.as_slice() {
+            self.finish_dna()?;
+            self.dna = p.dna.to_vec();
+        }
+        let location = p.location as usize;
+        match &self.mode {
+            &Mode::Window(window) => {
+                if self.open.front().is_none_or(|(r, _)| !r.contains(&location)) {
+                    self.close_before(usize::MAX)?;
+                    let start = (location - 1) / window * window + 1;
+                    self.open.push_back((start..start + window, new_totals()));
+                }
+            }
+            Mode::Bed(intervals, _) => {
+                let ranges = intervals.get(&self.dna).map_or(&[][..], Vec::as_slice);
+                while let Some(r) = ranges.get(self.next) && r.start <= location {
+                    self.open.push_back((r.clone(), new_totals()));
+                    self.next += 1;
+                }
+                self.close_before(location)?;
+            }
+        }
+        for (r, totals) in &mut self.open {
+            if r.contains(&location) {
+                totals[p.conversion as usize].add(p);
+            }
+        }
+        Ok(())
+    }
+
+    /// writes the open regions from the front which end at or before `end`
+    fn close_before(&mut self, end: usize) -> Result<()> {
+        while let Some((r, _)) = self.open.front() && r.end <= end {
+            let (r, mut totals) = self.open.pop_front().unwrap();
+            totals.iter_mut().for_each(Totals::close_site);
+            self.write(&r, &totals)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, r: &Range<usize>, totals: &[Totals]) -> Result<()> {
+        let dna = str::from_utf8(&self.dna).unwrap();
+        let end = (self.dna_len)(&self.dna).map_or(r.end - 1, |len| (r.end - 1).min(len));
+        for (conversion, t) in totals.iter().enumerate() {
+            // windows without sites are left out, BED intervals are not
+            if t.sites == 0 && matches!(self.mode, Mode::Window(_)) {
+                continue;
+            }
+            write!(self.output, "{}\t{}\t{}\t{}\t{}\t{}\t", dna, r.start - 1, end, t.sites, t.converted, t.unconverted)?;
+            if t.sites > 0 {
+                let weighted = t.converted as f64 / (t.converted + t.unconverted) as f64;
+                write!(self.output, "{:.6}\t{:.6}", t.rate_sum / t.sites as f64, weighted)?;
+            } else {
+                write!(self.output, ".\t.")?;
+            }
+            if multiple_base_changes() {
+                write!(self.output, "\t{}", base_change_label(conversion as u8))?;
+            }
+            writeln!(self.output)?;
+        }
+        Ok(())
+    }
+
+    /// writes the remaining regions of the current dna
+    fn finish_dna(&mut self) -> Result<()> {
+        if let Mode::Bed(intervals, _) = &self.mode
+          && let Some(ranges) = intervals.get(&self.dna) {
+            let rest = ranges[self.next..].iter().map(|r| (r.clone(), new_totals()));
+            self.open.extend(rest.collect::<Vec<_>>());
+        }
+        self.close_before(usize::MAX)?;
+        self.done.insert(std::mem::take(&mut self.dna));
+        self.next = 0;
+        Ok(())
+    }
+
+    /// writes the remaining regions, BED intervals of dnas without written
+    /// positions last
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_dna()?;
+        if let Mode::Bed(_, order) = &self.mode {
+            for dna in order.clone() {
+                if !self.done.contains(&dna) {
+                    self.dna = dna;
+                    self.finish_dna()?;
+                }
+            }
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+fn new_totals() -> Vec<Totals> {
+    vec![Totals::default(); ARGS.base_change.len()]
+}
+
+#[cfg(test)]
+fn site(dna: &'static [u8], location: isize, strand: u8, conversion: u8, sample: u32, converted: usize, unconverted: usize) -> Position<'static> {
+    let mut p = Position::new(dna, location);
+    p.strand = Some(strand);
+    p.conversion = conversion;
+    p.sample = sample;
+    for _ in 0..converted {
+        p.converted.push(b'I');
+    }
+    for _ in 0..unconverted {
+        p.unconverted.push(b'I');
+    }
+    p
+}
+
+#[cfg(test)]
+/// the rows written for `positions`, in a file named `name` of the temporary
+/// directory
+fn aggregate(name: &str, bed: Option<&str>, window: Option<usize>, positions: &[Position<'static>]) -> Vec<String> {
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("hisat-3n-table.{}.{}.tsv", std::process::id(), name));
+    let bed_path = dir.join(format!("hisat-3n-table.{}.{}.bed", std::process::id(), name));
+    if let Some(bed) = bed {
+        std::fs::write(&bed_path, bed).unwrap();
+    }
+    let dna_len = |dna: &[u8]| (dna == b"chr1").then_some(15);
+    let mut aggregator = Aggregator::new(&path, bed.map(|_| bed_path.as_path()), window, dna_len).unwrap();
+    for p in positions {
+        aggregator.add(p).unwrap();
+    }
+    aggregator.finish().unwrap();
+    let rows = std::fs::read_to_string(&path).unwrap().lines().skip(1).map(str::to_owned).collect();
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(bed_path);
+    rows
+}
+
+#[cfg(test)]
+fn test_positions() -> Vec<Position<'static>> {
+    // a C,T site on '+' in two samples, one on '-' and a G,A site
+    vec![
+        site(b"chr1", 3, b'+', 0, 0, 1, 1),
+        site(b"chr1", 3, b'+', 0, 1, 1, 0),
+        site(b"chr1", 5, b'-', 0, 0, 0, 2),
+        site(b"chr1", 12, b'+', 1, 1, 2, 0),
+        site(b"chr2", 1, b'+', 0, 0, 0, 1),
+    ]
+}
+
+#[test]
+fn test_aggregate_windows() {
+    assert_eq!(aggregate("windows", None, Some(10), &test_positions()), [
+        "chr1\t0\t10\t2\t2\t3\t0.333333\t0.400000\tC>T",
+        "chr1\t10\t15\t1\t2\t0\t1.000000\t1.000000\tG>A",
+        "chr2\t0\t10\t1\t0\t1\t0.000000\t0.000000\tC>T",
+    ]);
+}
+
+#[test]
+fn test_aggregate_bed() {
+    // BED intervals may overlap, and are written even without sites
+    let bed = "chr1\t0\t4\nchr1\t2\t20\nchr3\t0\t5\n";
+    assert_eq!(aggregate("bed", Some(bed), None, &test_positions()), [
+        "chr1\t0\t4\t1\t2\t1\t0.666667\t0.666667\tC>T",
+        "chr1\t0\t4\t0\t0\t0\t.\t.\tG>A",
+        "chr1\t2\t15\t2\t2\t3\t0.333333\t0.400000\tC>T",
+        "chr1\t2\t15\t1\t2\t0\t1.000000\t1.000000\tG>A",
+        "chr3\t0\t5\t0\t0\t0\t.\t.\tC>T",
+        "chr3\t0\t5\t0\t0\t0\t.\t.\tG>A",
+    ]);
+}
@@ -1,5 +1,8 @@
-use crate::utils::{md_get_next_segment, ChunkIterator, CigarIterator, StringSearchState};
-use crate::ARGS;
+use ahash::{AHashMap, AHashSet};
+
+use crate::context::Context;
+use crate::utils::{md_get_next_segment, ChunkIterator, CigarIterator, StringSearchState, BASE_CHARS};
+use crate::{BaseChange, ARGS};
 
 #[derive(Debug, Default)]
 pub struct PosQuality {
@@ -7,6 +10,12 @@ pub struct PosQuality {
     pub qual: u8,
     pub converted: bool,
     pub remove: bool,
+    /// 0-based sequencing cycle, counted from the 5' end of the read
+    pub cycle: u32,
+    /// inside the `--ignore-5p`/`--ignore-3p` cycles, excluded from `Position`s
+    pub trimmed: bool,
+    /// index of the `--base-change` this base is counted for
+    pub conversion: u8,
 }
 
 impl PosQuality {
@@ -18,20 +27,43 @@ impl PosQuality {
         }
     }
 
-    pub fn set_qual(&mut self, qual: u8, converted: bool) {
+    pub fn set_qual(&mut self, qual: u8, converted: bool, conversion: u8) {
         self.qual = qual;
         self.converted = converted;
+        self.conversion = conversion;
         self.remove = false;
     }
 }
 
+/// the `--base-change` converting reference `base` on `strand`
+pub fn conversion_from(strand: u8, base: u8) -> Option<u8> {
+    ARGS.base_change
+        .iter()
+        .position(|&((from, from_comp), _)| match strand {
+            b'+' => base == from,
+            b'-' => base == from_comp,
+            _ => false,
+        })
+        .map(|c| c as u8)
+}
+
+fn converted_to(strand: u8, conversion: u8) -> u8 {
+    let (_, (to, to_comp)) = ARGS.base_change[conversion as usize];
+    if strand == b'+' { to } else { to_comp }
+}
+
 pub struct Alignment<'a> {
+    pub name: &'a [u8],
     pub dna: &'a [u8],
     pub location: isize,
     pub mate_location: isize,
     pub flag: i32,
     pub mapped: bool,
+    /// the converted strand, '+', '-' or 0 if unknown. taken from `YZ`
+    /// first, then resolved by `--strand-inference`.
     pub strand: u8,
+    /// the strand given by the `XG`/`XR`/`YD`/`ZS` tags of other aligners
+    pub tag_strand: u8,
     pub sequence: &'a [u8],
     pub quality: &'a [u8],
     pub unique: bool,
@@ -44,6 +76,24 @@ pub struct Alignment<'a> {
     pub sequence_covered_length: usize,
     pub overlap: bool,
     pub paired: bool,
+    /// from a strand not sequenced in the `--library-type`
+    pub off_library: bool,
+    /// index of the sample, its input of several `--alignments` or its value
+    /// of the `--group-tag`
+    pub sample: u32,
+    /// without a value of the `--group-tag`
+    pub ungrouped: bool,
+    /// the value of the `--umi-tag`, which identifies the read instead of
+    /// its name
+    pub umi: &'a [u8],
+}
+
+/// the 0-based sequencing cycle of the `i`th aligned base of a read of
+/// `seq_len` bases, and whether it is one of the first `ignore_5p` or the
+/// last `ignore_3p` cycles. reverse reads are aligned from their 3' end.
+fn read_cycle(i: usize, seq_len: usize, reverse: bool, ignore_5p: usize, ignore_3p: usize) -> (usize, bool) {
+    let cycle = if reverse { seq_len - 1 - i } else { i };
+    (cycle, cycle < ignore_5p || cycle + ignore_3p >= seq_len)
 }
 
 // static debugfile: std::sync::LazyLock<std::sync::Mutex<File>> = std::sync::LazyLock::new(|| std::sync::Mutex::new(File::create("test2.check").unwrap()));
@@ -54,11 +104,16 @@ impl<'a> Alignment<'a> {
             return Err(());
         }
         let mut a = Self::new();
+        let groups = crate::GROUPS.get();
+        a.ungrouped = groups.is_some();
+        let group_tag = crate::group_tag().map(str::as_bytes);
+        let umi_tag = crate::umi_tag().map(str::as_bytes);
 
         let iter = memchr::memchr_iter(b'\t', data);
         let mut s = ChunkIterator::new(data, iter);
         // 0
-        a.read_name_id = Self::name_hash_str(s.next().ok_or(())?);
+        a.name = s.next().ok_or(())?;
+        a.read_name_id = Self::name_hash_str(a.name);
         // 1
         a.flag = atoi_simd::parse(s.next().ok_or(())?).map_err(|_| ())?;
         a.mapped = (a.flag & 4) == 0;
@@ -89,12 +144,51 @@ impl<'a> Alignment<'a> {
                 a.md = &s[5..];
             } else if s.starts_with(b"NM") {
                 a.nh = atoi_simd::parse(&s[5..]).map_err(|_| ())?;
+            } else if let Some(groups) = groups
+              && s.len() >= 5 && s[2] == b':' && Some(&s[..2]) == group_tag {
+                if let Some(&sample) = groups.get(&s[5..]) {
+                    a.sample = sample;
+                    a.ungrouped = false;
+                }
+            } else if umi_tag.is_some() && s.len() >= 5 && s[2] == b':' && Some(&s[..2]) == umi_tag {
+                a.umi = &s[5..];
+                a.read_name_id = Self::name_hash_str(a.umi);
             } else if s.starts_with(b"YZ") {
                 a.strand = *s.last().ok_or(())?;
+            } else if s.starts_with(b"XG:Z:") {
+                // Bismark: the converted genome strand
+                a.tag_strand = match &s[5..] {
+                    b"CT" => b'+',
+                    b"GA" => b'-',
+                    _ => 0,
+                };
+            } else if s.starts_with(b"XR:Z:") && a.tag_strand == 0 {
+                // Bismark: the converted read strand, reverse complemented
+                // with the read for the OB and CTOT strands
+                let reverse = (a.flag & 16) != 0;
+                a.tag_strand = match &s[5..] {
+                    b"CT" if !reverse => b'+',
+                    b"GA" if reverse => b'+',
+                    b"CT" | b"GA" => b'-',
+                    _ => 0,
+                };
+            } else if s.starts_with(b"YD:Z:") {
+                // bwa-meth
+                a.tag_strand = match s.get(5) {
+                    Some(b'f') => b'+',
+                    Some(b'r') => b'-',
+                    _ => 0,
+                };
+            } else if s.starts_with(b"ZS:Z:") {
+                // BSMAP: the first character is the reference strand
+                a.tag_strand = match s.get(5) {
+                    Some(&c @ (b'+' | b'-')) => c,
+                    _ => 0,
+                };
             }
         }
 
-        if (ARGS.unique_only && !a.unique) || (ARGS.multiple_only && a.unique) {
+        if a.filtered_by_uniqueness() {
             return Ok(a);
         }
         a.append_base();
@@ -103,6 +197,7 @@ impl<'a> Alignment<'a> {
 
     fn new() -> Self {
         Self {
+            name: Default::default(),
             dna: Default::default(),
             location: -1,
             mate_location: -1,
@@ -120,10 +215,20 @@ impl<'a> Alignment<'a> {
             overlap: false,
             paired: false,
             strand: Default::default(),
+            tag_strand: 0,
+            off_library: false,
+            sample: 0,
+            ungrouped: false,
+            umi: Default::default(),
             cigar: Default::default(),
         }
     }
 
+    /// whether `--unique-only` or `--multiple-only` skips the read
+    pub fn filtered_by_uniqueness(&self) -> bool {
+        (ARGS.unique_only && !self.unique) || (ARGS.multiple_only && self.unique)
+    }
+
     pub fn name_hash_str(name: &[u8]) -> u64 {
         let mut hash: u64 = 0;
         let a: u64 = 63689;
@@ -134,6 +239,63 @@ impl<'a> Alignment<'a> {
         hash
     }
 
+    /// (converted, total) bases of this read outside the `counted` reference
+    /// contexts, e.g. CH sites with `--cg-only`. bases of base changes
+    /// without contexts are never off-target.
+    pub fn offtarget_conversions(&self, text: &[u8], counted: impl Fn(Context) -> bool) -> (usize, usize) {
+        let mut converted = 0;
+        let mut total = 0;
+        for base in &self.bases {
+            if base.remove || base.trimmed {
+                continue;
+            }
+            let location = (self.location + base.ref_pos) as usize;
+            if location == 0 || location > text.len() {
+                continue;
+            }
+            let Some(context) = Context::of(text, location, self.strand, base.conversion) else {
+                continue;
+            };
+            if counted(context) {
+                continue;
+            }
+            total += 1;
+            if base.converted {
+                converted += 1;
+            }
+        }
+        (converted, total)
+    }
+
+    /// aligned bases of this read on N, IUPAC ambiguity codes or other bytes
+    /// than ACGT of the reference `text`, which are never counted
+    pub fn ambiguous_bases(&self, text: &[u8]) -> usize {
+        let mut start = (self.location as usize).saturating_sub(1);
+        let mut ambiguous = 0;
+        for (len, symbol) in CigarIterator::new(self.cigar) {
+            match symbol {
+                b'M' | b'=' | b'X' => {
+                    let bases = &text[start.min(text.len())..(start + len).min(text.len())];
+                    ambiguous += bases.iter().filter(|b| !BASE_CHARS.contains(&b.to_ascii_uppercase())).count();
+                    start += len;
+                }
+                b'D' | b'N' => start += len,
+                _ => {}
+            }
+        }
+        ambiguous
+    }
+
+    /// whether the read fails `--max-offtarget-conversions` or
+    /// `--max-offtarget-conversion-fraction`.
+    pub fn incompletely_converted(&self, text: &[u8]) -> bool {
+        if ARGS.max_offtarget_conversions.is_none() && ARGS.max_offtarget_conversion_fraction.is_none() {
+            return false;
+        }
+        let (converted, total) = self.offtarget_conversions(text, Context::is_counted);
+        too_many_conversions(converted, total, ARGS.max_offtarget_conversions, ARGS.max_offtarget_conversion_fraction)
+    }
+
     fn adjust_pos(&mut self) -> usize {
         let mut read_pos = 0;
         let mut return_pos = 0;
@@ -179,6 +341,85 @@ impl<'a> Alignment<'a> {
         return_pos
     }
 
+    /// whether the fragment is on the forward strand, i.e. R1 on the forward
+    /// strand or R2 on the reverse strand
+    fn fragment_forward(&self) -> bool {
+        let reverse = (self.flag & 16) != 0;
+        let r2 = (self.flag & 0x80) != 0;
+        reverse == r2
+    }
+
+    /// the strand implied by the FLAG for `--library-type`, or 0 if reads of
+    /// the library come from both strands in either orientation
+    fn flag_strand(&self) -> u8 {
+        ARGS.library_type.strand(self.fragment_forward())
+    }
+
+    /// the strand with more mismatches that look like one of `base_changes`,
+    /// or 0 on ties. `pos` is the first aligned read position.
+    fn conversion_strand(&self, mut pos: usize, base_changes: &[BaseChange]) -> u8 {
+        let (mut plus, mut minus) = (0, 0);
+        let mut search = StringSearchState::new(self.md);
+        let mut seg = Vec::<u8>::new();
+        while md_get_next_segment(&mut search, &mut seg) {
+            let ref_base = seg.first().unwrap();
+            if ref_base.is_ascii_digit() {
+                let len: usize = atoi_simd::parse(seg.as_slice()).unwrap();
+                for _ in 0..len {
+                    while self.bases[pos].remove {
+                        pos += 1;
+                    }
+                    pos += 1;
+                }
+            } else if ref_base.is_ascii_alphabetic() {
+                while self.bases[pos].remove {
+                    pos += 1;
+                }
+                let change = (ref_base.to_ascii_uppercase(), self.sequence[pos]);
+                for &((from, from_comp), (to, to_comp)) in base_changes {
+                    if change == (from, to) {
+                        plus += 1;
+                    } else if change == (from_comp, to_comp) {
+                        minus += 1;
+                    }
+                }
+                pos += 1;
+            }
+        }
+        match plus.cmp(&minus) {
+            std::cmp::Ordering::Greater => b'+',
+            std::cmp::Ordering::Less => b'-',
+            std::cmp::Ordering::Equal => 0,
+        }
+    }
+
+    /// resolves `strand` by the `--strand-inference` sources, in order, then
+    /// by the FLAG for directional and PBAT libraries
+    fn infer_strand(&mut self, pos: usize) {
+        let yz = std::mem::take(&mut self.strand);
+        for source in &ARGS.strand_inference {
+            self.strand = match source {
+                StrandSource::Yz => yz,
+                StrandSource::Tags => self.tag_strand,
+                StrandSource::Flag => self.flag_strand(),
+                StrandSource::Conversions => self.conversion_strand(pos, &ARGS.base_change),
+            };
+            if self.strand != 0 {
+                break;
+            }
+        }
+        if self.strand == 0 {
+            self.strand = self.flag_strand();
+        }
+        // OT/OB reads are read from the converted strand, CTOT/CTOB ones
+        // from its complement
+        let original = self.fragment_forward() == (self.strand == b'+');
+        if self.strand != 0 && !ARGS.library_type.sequences(original) {
+            self.strand = 0;
+            self.off_library = true;
+        }
+    }
+
     fn append_base(&mut self) {
         // TODO: check understanding
         // original impl checks sequence_covered_length, which should
@@ -187,12 +428,19 @@ impl<'a> Alignment<'a> {
             return;
         }
 
-        self.bases.reserve_exact(self.sequence.len());
-        for i in 0..self.sequence.len() {
-            self.bases.push(PosQuality::new(i as isize));
+        let seq_len = self.sequence.len();
+        let reverse = (self.flag & 16) != 0;
+        self.bases.reserve_exact(seq_len);
+        for i in 0..seq_len {
+            let mut base = PosQuality::new(i as isize);
+            let (cycle, trimmed) = read_cycle(i, seq_len, reverse, ARGS.ignore_5p, ARGS.ignore_3p);
+            base.cycle = cycle as u32;
+            base.trimmed = trimmed;
+            self.bases.push(base);
         }
 
         let mut pos = self.adjust_pos();
+        self.infer_strand(pos);
         let mut search = StringSearchState::new(self.md);
         let mut seg = Vec::<u8>::new();
         while md_get_next_segment(&mut search, &mut seg) {
@@ -203,10 +451,8 @@ impl<'a> Alignment<'a> {
                     while self.bases[pos].remove {
                         pos += 1;
                     }
-                    if self.strand == b'+' && self.sequence[pos] == ARGS.base_change.0.0
-                      || self.strand == b'-' && self.sequence[pos] == ARGS.base_change.0.1
-                    {
-                        self.bases[pos].set_qual(self.quality[pos], false);
+                    if let Some(c) = conversion_from(self.strand, self.sequence[pos]) {
+                        self.bases[pos].set_qual(self.quality[pos], false, c);
                     } else {
                         self.bases[pos].remove = true;
                     }
@@ -216,10 +462,11 @@ impl<'a> Alignment<'a> {
                 while self.bases[pos].remove {
                     pos += 1;
                 }
-                if self.strand == b'+' && *ref_base == ARGS.base_change.0.0 && self.sequence[pos] == ARGS.base_change.1.0
-                  || self.strand == b'-' && *ref_base == ARGS.base_change.0.1 && self.sequence[pos] == ARGS.base_change.1.1
+                let ref_base = ref_base.to_ascii_uppercase();
+                if let Some(c) = conversion_from(self.strand, ref_base)
+                  && self.sequence[pos] == converted_to(self.strand, c)
                 {
-                    self.bases[pos].set_qual(self.quality[pos], true);
+                    self.bases[pos].set_qual(self.quality[pos], true, c);
                 } else {
                     self.bases[pos].remove = true;
                 }
@@ -228,3 +475,241 @@ impl<'a> Alignment<'a> {
         }
     }
 }
+
+/// whether `converted` of `total` off-target bases exceed `max` or
+/// `max_fraction`
+fn too_many_conversions(converted: usize, total: usize, max: Option<usize>, max_fraction: Option<f64>) -> bool {
+    if max.is_some_and(|max| converted > max) {
+        return true;
+    }
+    total > 0 && max_fraction.is_some_and(|max| converted as f64 / total as f64 > max)
+}
+
+/// where the converted strand of a read is taken from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum StrandSource {
+    /// the `YZ` tag of HISAT-3N
+    Yz,
+    /// the `XG`/`XR` tags of Bismark, `YD` of bwa-meth or `ZS` of BSMAP
+    Tags,
+    /// the FLAG, for directional and PBAT `--library-type`s
+    Flag,
+    /// the strand with more conversion-like mismatches on the read
+    Conversions,
+}
+
+/// which of the four bisulfite strands (OT, OB, CTOT, CTOB) reads of a
+/// library come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LibraryType {
+    /// R1 from OT/OB, R2 from CTOT/CTOB
+    Directional,
+    /// any read from any of the four strands, as in single-cell libraries
+    /// with random priming
+    #[value(alias = "single-cell")]
+    NonDirectional,
+    /// post-bisulfite adapter tagging: R1 from CTOT/CTOB, R2 from OT/OB
+    Pbat,
+}
+
+impl LibraryType {
+    /// whether reads from an original (OT/OB) or complementary (CTOT/CTOB)
+    /// strand are sequenced in the library
+    fn sequences(self, original: bool) -> bool {
+        match self {
+            LibraryType::Directional => original,
+            LibraryType::Pbat => !original,
+            LibraryType::NonDirectional => true,
+        }
+    }
+
+    /// the converted strand of a read of the library whose fragment is on the
+    /// forward strand or not (see `fragment_forward`), or 0 if it may be
+    /// either
+    fn strand(self, fragment_forward: bool) -> u8 {
+        match self {
+            LibraryType::Directional => if fragment_forward { b'+' } else { b'-' },
+            LibraryType::Pbat => if fragment_forward { b'-' } else { b'+' },
+            LibraryType::NonDirectional => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReadIdentity {
+    /// 64-bit hash of the read name, different reads may collide
+    Hash64,
+    /// the full read name, interned per task
+    Name,
+}
+
+/// gives the reads of a task collision free ids with `--read-identity name`,
+/// and counts the 64-bit hash collisions seen meanwhile. reads of different
+/// samples never share a name. reads are named by their UMI with
+/// `--umi-tag`.
+#[derive(Default)]
+pub struct ReadNames<'a> {
+    ids: AHashMap<(u32, &'a [u8]), u64>,
+    hashes: AHashSet<(u32, u64)>,
+    pub collisions: usize,
+}
+
+impl<'a> ReadNames<'a> {
+    pub fn intern(&mut self, a: &Alignment<'a>) -> u64 {
+        if ARGS.read_identity == ReadIdentity::Hash64 {
+            return a.read_name_id;
+        }
+        let name = if a.umi.is_empty() { a.name } else { a.umi };
+        self.intern_name(a.sample, name, a.read_name_id)
+    }
+
+    /// the id of `name` in `sample`, whose 64-bit hash is `hash`
+    fn intern_name(&mut self, sample: u32, name: &'a [u8], hash: u64) -> u64 {
+        let next = self.ids.len() as u64;
+        let mut new = false;
+        let id = *self.ids.entry((sample, name)).or_insert_with(|| {
+            new = true;
+            next
+        });
+        if new && !self.hashes.insert((sample, hash)) {
+            self.collisions += 1;
+        }
+        id
+    }
+}
+
+#[test]
+fn test_incompletely_converted() {
+    // C at 2, 5 and 8 (all CHH), the first two converted
+    let text = b"TCATCATCAT";
+    let line = b"r1\t0\tchr1\t1\t60\t10M\t*\t0\t0\tTTATTATCAT\tIIIIIIIIII\tMD:Z:1C2C5\tYZ:A:+";
+    let a = Alignment::from_file(line).unwrap();
+    assert_eq!(a.offtarget_conversions(text, |_| true), (0, 0));
+    assert_eq!(a.offtarget_conversions(text, |c| c == Context::Cg), (2, 3));
+
+    assert!(!too_many_conversions(2, 3, None, None));
+    assert!(too_many_conversions(2, 3, Some(1), None));
+    assert!(!too_many_conversions(2, 3, Some(2), None));
+    assert!(too_many_conversions(2, 3, None, Some(0.5)));
+    assert!(!too_many_conversions(2, 3, None, Some(0.7)));
+    assert!(!too_many_conversions(0, 0, None, Some(0.0)));
+}
+
+#[cfg(test)]
+fn tag_strand(flag: i32, tag: &str) -> u8 {
+    let line = format!("r1\t{}\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\t{}", flag, tag);
+    Alignment::from_file(line.as_bytes()).unwrap().tag_strand
+}
+
+#[test]
+fn test_tag_strand() {
+    assert_eq!(tag_strand(0, "XG:Z:CT"), b'+');
+    assert_eq!(tag_strand(16, "XG:Z:GA"), b'-');
+    assert_eq!(tag_strand(0, "XG:Z:NN"), 0);
+    // XR is reverse complemented with reverse reads, and XG wins over it
+    assert_eq!(tag_strand(0, "XR:Z:CT"), b'+');
+    assert_eq!(tag_strand(0, "XR:Z:GA"), b'-');
+    assert_eq!(tag_strand(16, "XR:Z:CT"), b'-');
+    assert_eq!(tag_strand(16, "XR:Z:GA"), b'+');
+    assert_eq!(tag_strand(16, "XG:Z:CT\tXR:Z:CT"), b'+');
+    assert_eq!(tag_strand(0, "YD:Z:f"), b'+');
+    assert_eq!(tag_strand(0, "YD:Z:r"), b'-');
+    assert_eq!(tag_strand(0, "ZS:Z:-+"), b'-');
+    assert_eq!(tag_strand(0, "ZS:Z:++"), b'+');
+    assert_eq!(tag_strand(0, "ZS:Z:?"), 0);
+    assert_eq!(tag_strand(0, "NM:i:0"), 0);
+}
+
+#[cfg(test)]
+fn conversion_strand(md: &[u8], sequence: &[u8]) -> u8 {
+    let mut a = Alignment::new();
+    a.md = md;
+    a.cigar = b"8M";
+    a.sequence = sequence;
+    a.bases = (0..sequence.len()).map(|i| PosQuality::new(i as isize)).collect();
+    let pos = a.adjust_pos();
+    // C,T only: with G,A as well, every mismatch fits both strands
+    a.conversion_strand(pos, &ARGS.base_change[..1])
+}
+
+#[test]
+fn test_conversion_strand() {
+    // ACGTCCGT
+    assert_eq!(conversion_strand(b"1C2C3", b"ATGTTCGT"), b'+');
+    assert_eq!(conversion_strand(b"2G3G1", b"ACATCCAT"), b'-');
+    assert_eq!(conversion_strand(b"1C0G5", b"ATATCCGT"), 0);
+    assert_eq!(conversion_strand(b"8", b"ACGTCCGT"), 0);
+    // other mismatches count for neither strand
+    assert_eq!(conversion_strand(b"0A0C6", b"GTGTCCGT"), b'+');
+}
+
+#[test]
+fn test_library_type() {
+    // an OT read: R1 forward, converted on '+'
+    assert_eq!(LibraryType::Directional.strand(true), b'+');
+    assert_eq!(LibraryType::Directional.strand(false), b'-');
+    assert_eq!(LibraryType::Pbat.strand(true), b'-');
+    assert_eq!(LibraryType::Pbat.strand(false), b'+');
+    assert_eq!(LibraryType::NonDirectional.strand(true), 0);
+    for forward in [true, false] {
+        for library in [LibraryType::Directional, LibraryType::Pbat] {
+            // the strand given by the FLAG is always sequenced
+            let original = forward == (library.strand(forward) == b'+');
+            assert!(library.sequences(original));
+            assert!(!library.sequences(!original));
+        }
+    }
+    assert!(LibraryType::NonDirectional.sequences(true) && LibraryType::NonDirectional.sequences(false));
+    assert_eq!(<LibraryType as clap::ValueEnum>::from_str("single-cell", false), Ok(LibraryType::NonDirectional));
+}
+
+#[test]
+fn test_ambiguous_bases() {
+    let text = b"ACNTRCGT*-.A";
+    let ambiguous = |location: usize, cigar: &str| {
+        let line = format!("r1\t0\tchr1\t{}\t60\t{}\t*\t0\t0\tACGTAC\tIIIIII", location, cigar);
+        Alignment::from_file(line.as_bytes()).unwrap().ambiguous_bases(text)
+    };
+    assert_eq!(ambiguous(1, "6M"), 2);
+    // clipped and inserted bases are not on the reference, deleted and
+    // skipped ones not aligned
+    assert_eq!(ambiguous(3, "2S4M"), 2);
+    assert_eq!(ambiguous(1, "2M1I1M2D2M"), 1);
+    assert_eq!(ambiguous(4, "1M4N5M"), 3);
+    // reads running off the end of the dna
+    assert_eq!(ambiguous(10, "6M"), 2);
+}
+
+#[test]
+fn test_read_cycle() {
+    let cycles = |reverse, ignore_5p, ignore_3p| -> Vec<_> {
+        (0..6).map(|i| read_cycle(i, 6, reverse, ignore_5p, ignore_3p)).collect()
+    };
+    assert_eq!(cycles(false, 2, 1), [(0, true), (1, true), (2, false), (3, false), (4, false), (5, true)]);
+    // the 5' end of a reverse read is at the end of its alignment
+    assert_eq!(cycles(true, 2, 1), [(5, true), (4, false), (3, false), (2, false), (1, true), (0, true)]);
+    assert!(cycles(false, 0, 0).iter().all(|&(_, trimmed)| !trimmed));
+    assert!(cycles(true, 3, 3).iter().all(|&(_, trimmed)| trimmed));
+
+    let a = Alignment::from_file(b"r1\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\tYZ:A:+").unwrap();
+    let cycles: Vec<_> = a.bases.iter().map(|b| (b.cycle, b.trimmed)).collect();
+    assert_eq!(cycles, [(3, false), (2, false), (1, false), (0, false)]);
+}
+
+#[test]
+fn test_read_names() {
+    let mut names = ReadNames::default();
+    let r1 = names.intern_name(0, b"r1", 1);
+    let r2 = names.intern_name(0, b"r2", 2);
+    assert_ne!(r1, r2);
+    assert_eq!(names.intern_name(0, b"r1", 1), r1);
+    // the same name in another sample is another read
+    assert_ne!(names.intern_name(1, b"r1", 1), r1);
+    assert_eq!(names.collisions, 0);
+    // r3 hashes like r1: it still gets an id of its own, and the collision
+    // is counted once
+    let r3 = names.intern_name(0, b"r3", 1);
+    assert!(![r1, r2].contains(&r3));
+    assert_eq!(names.intern_name(0, b"r3", 1), r3);
+    assert_eq!(names.collisions, 1);
+}
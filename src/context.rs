@@ -0,0 +1,84 @@
+use crate::utils::asc2dnacomp;
+use crate::ARGS;
+
+/// trinucleotide context of a converted base, read on its own strand
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Context {
+    #[value(name = "CG")]
+    Cg,
+    #[value(name = "CHG")]
+    Chg,
+    #[value(name = "CHH")]
+    Chh,
+}
+
+impl Context {
+    pub const ALL: [Context; 3] = [Context::Cg, Context::Chg, Context::Chh];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Context::Cg => "CG",
+            Context::Chg => "CHG",
+            Context::Chh => "CHH",
+        }
+    }
+
+    /// the context of a base of the `conversion`th `--base-change` at 1-based
+    /// `location` on `strand`. only conversions from C or G have one; a
+    /// converted G is classified as the C of the other strand.
+    pub fn of(text: &[u8], location: usize, strand: u8, conversion: u8) -> Option<Context> {
+        let ((from, _), _) = ARGS.base_change[conversion as usize];
+        match from {
+            b'C' => Some(Context::classify(text, location, strand)),
+            b'G' => Some(Context::classify(text, location, if strand == b'+' { b'-' } else { b'+' })),
+            _ => None,
+        }
+    }
+
+    /// classifies the base at 1-based `location` of `text`. the two following
+    /// bases are taken downstream on `strand` (complemented for '-') case
+    /// insensitively; missing bases at the ends of the dna and ambiguity codes
+    /// count as H.
+    pub fn classify(text: &[u8], location: usize, strand: u8) -> Context {
+        let (next1, next2) = if strand == b'-' {
+            let before = |n: usize| if location > n { asc2dnacomp(text[location - 1 - n].to_ascii_uppercase()) } else { b'\0' };
+            (before(1), before(2))
+        } else {
+            let after = |n: usize| text.get(location - 1 + n).map_or(b'\0', u8::to_ascii_uppercase);
+            (after(1), after(2))
+        };
+        if next1 == b'G' {
+            Context::Cg
+        } else if next2 == b'G' {
+            Context::Chg
+        } else {
+            Context::Chh
+        }
+    }
+
+    /// whether bases in this context are counted, according to `--cg-only`
+    /// and `--context`.
+    pub fn is_counted(self) -> bool {
+        if ARGS.cg_only {
+            return self == Context::Cg;
+        }
+        ARGS.context.is_empty() || ARGS.context.contains(&self)
+    }
+}
+
+#[test]
+fn test_context() {
+    // CpG at 2-3, CHG at 5 and CHH at 9 on '+'
+    let text = b"ACGTCAGTTCAA";
+    assert_eq!(Context::classify(text, 2, b'+'), Context::Cg);
+    assert_eq!(Context::classify(text, 5, b'+'), Context::Chg);
+    assert_eq!(Context::classify(text, 10, b'+'), Context::Chh);
+    // the G of the CpG read on '-'
+    assert_eq!(Context::classify(text, 3, b'-'), Context::Cg);
+    // the test arguments count C,T and G,A
+    assert_eq!(Context::of(text, 2, b'+', 0), Some(Context::Cg));
+    assert_eq!(Context::of(text, 3, b'+', 1), Some(Context::Cg));
+    assert_eq!(Context::of(text, 2, b'-', 1), Some(Context::Cg));
+    // the G at 7 is the C of a CTG on '-'
+    assert_eq!(Context::of(text, 7, b'+', 1), Some(Context::Chg));
+}
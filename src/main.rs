@@ -7,28 +7,51 @@
     cold_path,
 )]
 
+mod aggregate;
 mod alignment;
+mod context;
+mod mbias;
+mod matrix;
+mod output;
 mod position;
+mod pvalue;
+mod region;
+mod snp;
+mod sort;
+mod summary;
 mod task;
 mod utils;
 
-use position::{fill_positions, Position};
+use position::{merge_cpg_strands, ConflictPolicy, Position, PositionStorage, Positions, ReadIdTable};
 use rmp_serde::from_read;
-use task::{scan_alignment_segments, TaskResult};
+use alignment::{LibraryType, ReadIdentity, ReadNames, StrandSource};
+use context::Context;
+use matrix::MatrixWriter;
+use mbias::MBias;
+use aggregate::Aggregator;
+use output::{is_reported, SampleFormat, TableWriter};
+use pvalue::{ErrorRate, TestCount};
+use region::{Region, RegionFilter};
+use snp::SnpMask;
+use summary::{Stats, Summary};
+use task::{scan_alignment_segments, AuxValues, TaskOutput};
 use utils::asc2dnacomp;
 
-use std::{hint::cold_path, path::Path, sync::{mpsc, LazyLock}};
-use anyhow::Result;
+use std::{path::Path, sync::{mpsc, Arc, LazyLock, OnceLock}};
+use anyhow::{Context as _, Result};
 use memmap2::{Advice, Mmap};
-use rayon::{iter::{IntoParallelRefIterator, ParallelBridge, ParallelIterator}, ThreadPoolBuilder};
+use rayon::{iter::{IntoParallelRefIterator, ParallelIterator}, ThreadPoolBuilder};
 use clap::Parser;
 
 use std::{fs::File, path::PathBuf};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use ascii::{AsciiString, ToAsciiChar};
-use std::io::{Write, BufReader};
-use ahash::AHashMap;
-use crate::task::{Task2, TaskIter2};
+use std::io::BufReader;
+use ahash::{AHashMap, AHashSet};
+use crate::task::{block_sizes, issue_tasks, skipped_records, task_window, Task2, TaskIter2, TaskWindow};
+
+/// ((convert_from, complement), (convert_to, convert_to_complement))
+pub type BaseChange = ((u8, u8), (u8, u8));
 
 #[derive(clap::Parser, Debug)]
 #[command(version, about)]
@@ -36,9 +59,69 @@ struct Arguments {
     #[arg(
         long = "alignments",
         value_name = "alignmentFile",
-        help = "SORTED SAM filename. Please enter '-' for standard input."
+        required = true,
+        help = "SORTED SAM filename. Please enter '-' for standard input. Can be repeated to count several samples against one reference, see --sample-names and --sample-format."
+    )]
+    alignment_files: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "names",
+        help = "comma separated sample labels of the --alignments, in order (the file stems)."
+    )]
+    sample_names: Vec<String>,
+    #[arg(
+        long,
+        value_name = "tag",
+        conflicts_with = "sample_names",
+        value_parser = utils::parse_aux_tag,
+        help = "count the reads of each value of this aux tag (e.g. RG for read groups, CB for cell barcodes) as a sample of one --alignments file. reads without the tag are not counted."
+    )]
+    group_tag: Option<String>,
+    #[arg(
+        long,
+        value_name = "file",
+        help = "the --group-tag values to count, one per line in the first column (e.g. the barcodes.tsv of a cell caller). reads of other values are not counted (all values)."
+    )]
+    group_values: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "tag",
+        value_parser = utils::parse_aux_tag,
+        help = "count the bases of the reads sharing a value of this aux tag (e.g. UB) once per position and --group-tag value (e.g. cell), instead of the bases of a read name. Needs --group-tag or --barcode-mode. reads without the tag are not counted."
+    )]
+    umi_tag: Option<String>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "single-cell mode: group the reads by cell barcode (--group-tag, CB by default) and deduplicate them by UMI (--umi-tag, UB by default)."
+    )]
+    barcode_mode: bool,
+    #[arg(
+        long,
+        value_name = "prefix",
+        help = "also write the counts of each sample (cell) at each written site as sparse MatrixMarket matrices prefix.converted.mtx and prefix.unconverted.mtx, with rows named in prefix.samples.tsv and columns in prefix.sites.tsv."
+    )]
+    matrix_output: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SampleFormat::Long,
+        help = "table layout with several samples: long adds a sample column, wide writes one row per position with the converted and unconverted counts of each sample (no qualities), split writes one table per sample named like out.sample.tsv, with characters of the label other than letters, digits and ._+- replaced by _. --summary and --aggregate-output pool the samples."
+    )]
+    sample_format: SampleFormat,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "sort the alignments by coordinate first, for unsorted input. Sorted runs are spilled to --tmp-dir and merged."
+    )]
+    sort_input: bool,
+    #[arg(
+        long,
+        value_name = "dir",
+        help = "directory for the temporary files of --sort-input and of standard input (the system temporary directory)."
     )]
-    alignment_file: PathBuf,
+    tmp_dir: Option<PathBuf>,
     #[arg(
         long = "refIndex",
         value_name = "refFileIndex",
@@ -53,7 +136,7 @@ struct Arguments {
     output_name: PathBuf,
     #[arg(
         long, 
-        value_parser = |s: &str| -> Result<((u8, u8), (u8, u8)), String> {
+        value_parser = |s: &str| -> Result<BaseChange, String> {
             let s = Vec::from_iter(s.trim().split(','));
             if s.len() != 2 || !s.iter().all(|b| b.len() == 1) {
                 return Err("format error".to_owned())                
@@ -70,10 +153,10 @@ struct Arguments {
             }
             Ok(((from, from_comp), (to, to_comp)))
         },
-        help = "the char1 is the nucleotide converted from, the char2 is the nucleotide converted to."
+        required = true,
+        help = "the char1 is the nucleotide converted from, the char2 is the nucleotide converted to. Can be repeated (e.g. --base-change C,T --base-change A,G) to count several conversions in one pass; the table then gets a baseChange column."
     )]
-    /// ((convert_from, complement), (convert_to, convert_to_complement))
-    base_change: ((u8, u8), (u8, u8)),
+    base_change: Vec<BaseChange>,
     #[arg(
         short,
         long,
@@ -92,9 +175,78 @@ struct Arguments {
         short,
         long,
         default_value_t = false,
-        help = "only count CG and ignore CH in reference."
+        help = "only count CG and ignore CH in reference (for base changes from C or G)."
     )]
     cg_only: bool,
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "contexts",
+        help = "only count the given comma separated trinucleotide contexts (CG, CHG, CHH) of the converted base, for base changes from C or G (a G is classified as the C of the other strand). By default, all contexts are counted."
+    )]
+    context: Vec<Context>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "add a context column (CG, CHG, CHH, or '.' for base changes not from C or G) to the table."
+    )]
+    context_column: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "write one table per counted context, named by inserting the context before the extension of --output-name (e.g. out.CG.tsv). Every --base-change must be from C or G."
+    )]
+    split_contexts: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "merge the two strands of each CpG into one row at the C position, with strand '*'."
+    )]
+    merge_cpg_strands: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "only keep base counts and the mean base quality per position instead of the quality strings, for lower memory usage and smaller tables."
+    )]
+    counts_only: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PositionStorage::Auto,
+        help = "allocate a Position for every reference base of a Task (dense), or only for the covered ones (sparse, better for RRBS or targeted panels)."
+    )]
+    position_storage: PositionStorage,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ConflictPolicy::DropBoth,
+        help = "how to count a position covered twice by the same read (e.g. overlapping mates) with different conversion status."
+    )]
+    conflict_policy: ConflictPolicy,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ReadIdentity::Hash64,
+        help = "how reads are identified when deduplicating bases at a position. 'name' compares full read names and reports hash collisions."
+    )]
+    read_identity: ReadIdentity,
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "sources",
+        default_value = "yz",
+        help = "comma separated sources of the converted strand of a read, tried in order: yz (HISAT-3N YZ tag), tags (Bismark XG/XR, bwa-meth YD, BSMAP ZS), flag (FLAG, for directional and PBAT --library-type), conversions (the strand with more conversion-like mismatches). Reads without a strand are not counted."
+    )]
+    strand_inference: Vec<StrandSource>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LibraryType::NonDirectional,
+        help = "the bisulfite strands reads come from. directional and pbat libraries take the converted strand from the FLAG when --strand-inference gives none, and only count reads from OT/OB and CTOT/CTOB respectively; non-directional (alias single-cell) libraries count reads from all four strands."
+    )]
+    library_type: LibraryType,
     #[arg(
         short,
         long,
@@ -118,19 +270,177 @@ struct Arguments {
     threads: usize,
     #[arg(
         long,
-        default_value_t = 20000000,
-        help = "max number of Alignment record lines in a Task (20000)",
+        help = "max number of Alignment record lines in a Task (20000000, or derived from --max-memory)",
+    )]
+    align_block_size: Option<usize>,
+    #[arg(
+        long,
+        help = "max number of chromosome Position s in a Task (20000000, or derived from --max-memory)",
+    )]
+    ref_block_size: Option<usize>,
+    #[arg(
+        long,
+        value_name = "size",
+        value_parser = utils::parse_size,
+        help = "approximate memory budget for the Tasks in flight, e.g. 8G. Task sizes are derived from it unless --align-block-size or --ref-block-size is given.",
+    )]
+    max_memory: Option<usize>,
+    #[arg(
+        long,
+        value_name = "chr:start-end",
+        value_parser = region::parse_region,
+        help = "only count positions in this region (1-based, inclusive). Can be repeated.",
     )]
-    align_block_size: usize,
+    region: Vec<Region>,
     #[arg(
         long,
-        default_value_t = 20000000,
-        help = "max number of chromosome Position s in a Task (20000)",
+        value_name = "bedFile",
+        help = "only count positions in the intervals of this BED file. Can be repeated.",
+    )]
+    targets: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "bedFile",
+        help = "do not count positions in the intervals of this BED file. Can be repeated.",
+    )]
+    exclude: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "vcfFile",
+        help = "VCF or BCF file (plain or bgzipped) of known variants; their positions are not counted for any read, whatever its allele.",
+    )]
+    snp_vcf: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "snp_vcf",
+        help = "only mask the single-base variants that look like a conversion of --base-change (e.g. C>T on the '+' strand, G>A on the '-' strand).",
+    )]
+    snp_transitions_only: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "do not count soft-masked (lowercase, e.g. repeats) reference bases. By default, the reference is read case insensitively.",
     )]
-    ref_block_size: usize,
+    exclude_soft_masked: bool,
+    #[arg(
+        long,
+        value_name = "contig",
+        help = "unmethylated spike-in contig (e.g. lambda) to estimate the conversion rate from. Can be repeated.",
+    )]
+    unconverted_control: Vec<String>,
+    #[arg(
+        long,
+        value_name = "contig",
+        help = "methylated spike-in contig (e.g. pUC19) to estimate the non-conversion rate from. Can be repeated.",
+    )]
+    methylated_control: Vec<String>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "do not write the --unconverted-control and --methylated-control contigs to the table.",
+    )]
+    exclude_controls: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        help = "only write positions with at least N counted (converted + unconverted) bases (1)."
+    )]
+    min_coverage: u32,
+    #[arg(
+        long,
+        value_name = "rate",
+        value_parser = pvalue::parse_error_rate,
+        help = "add a pValue column testing whether the --test-count of a position exceeds this background rate. 'control' estimates it from the spike-ins: the conversion rate of --methylated-control for converted, the non-conversion rate of --unconverted-control for unconverted counts; the control contigs are then counted once more ahead of the run."
+    )]
+    error_rate: Option<ErrorRate>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = TestCount::Converted,
+        help = "the count tested against --error-rate."
+    )]
+    test_count: TestCount,
+    #[arg(
+        long,
+        value_name = "rho",
+        default_value_t = 0.0,
+        help = "overdispersion (intra-class correlation) of a beta-binomial test. 0 uses a binomial test (0)."
+    )]
+    overdispersion: f64,
+    #[arg(
+        long,
+        value_name = "aggregateFile",
+        help = "file name to save per region totals of the written positions (tsv format): covered sites, converted and unconverted counts, mean site rate and weighted rate. Regions are given by --aggregate-bed or --window."
+    )]
+    aggregate_output: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "bedFile",
+        requires = "aggregate_output",
+        conflicts_with = "window",
+        help = "aggregate over the intervals of this BED file (e.g. promoters); every interval gets a row."
+    )]
+    aggregate_bed: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "aggregate_output",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "aggregate over consecutive windows of N bases; windows without sites are left out."
+    )]
+    window: Option<usize>,
+    #[arg(
+        long,
+        value_name = "mbiasFile",
+        help = "file name to save the M-bias report (conversion rate by read cycle, for R1/R2 and strand, tsv format)."
+    )]
+    mbias_report: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "summaryFile",
+        help = "file name to save the run summary (records, filtered reads by reason, converted/unconverted totals by context, strand and base change, per chromosome coverage and timing), JSON or, for a .tsv name, key-value tsv."
+    )]
+    summary: Option<PathBuf>,
+    #[arg(
+        long = "ignore-5p",
+        value_name = "N",
+        default_value_t = 0,
+        help = "ignore the first N cycles from the 5' end of each read when counting bases (0)."
+    )]
+    ignore_5p: usize,
+    #[arg(
+        long = "ignore-3p",
+        value_name = "N",
+        default_value_t = 0,
+        help = "ignore the last N cycles at the 3' end of each read when counting bases (0)."
+    )]
+    ignore_3p: usize,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "drop reads with more than N converted bases outside the counted contexts (see --context and --cg-only), which indicate incomplete conversion."
+    )]
+    max_offtarget_conversions: Option<usize>,
+    #[arg(
+        long,
+        value_name = "F",
+        help = "drop reads whose converted fraction of bases outside the counted contexts (see --context and --cg-only) is above F."
+    )]
+    max_offtarget_conversion_fraction: Option<f64>,
 }
 
+#[cfg(not(test))]
 static ARGS: LazyLock<Arguments> = LazyLock::new(|| { Arguments::parse() });
+/// fixed arguments for the unit tests, whatever the test harness is given
+#[cfg(test)]
+static ARGS: LazyLock<Arguments> = LazyLock::new(|| {
+    Arguments::parse_from([
+        "hisat-3n-table", "--alignments", "test.sam", "--refIndex", "test.idx", "--output-name", "test.tsv",
+        "--base-change", "C,T", "--base-change", "G,A",
+    ])
+});
 
 fn static_mmap_str(p: &Path) -> &'static [u8] {
     let alignment_file = Box::new(File::open(p).unwrap());
@@ -145,7 +455,21 @@ fn static_mmap_str(p: &Path) -> &'static [u8] {
 
 // a comprehensive survey shows that LazyLock has no sync overhead after init
 // deref ops after init is just like normal deref ops
-static ALIGN_FILE: LazyLock<&'static [u8]> = LazyLock::new(|| static_mmap_str(&ARGS.alignment_file));
+/// the `--alignments`, with the sorted copies made by `--sort-input` and
+/// standard input spooled to a file in their place
+static ALIGN_PATHS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+/// the mapped `--alignments`, one per sample with several of them
+static ALIGN_FILES: LazyLock<Vec<&'static [u8]>> = LazyLock::new(|| {
+    ALIGN_PATHS.get().unwrap_or(&ARGS.alignment_files).iter().map(|p| static_mmap_str(p)).collect()
+});
+/// the sample labels, set with several samples
+static SAMPLES: OnceLock<Vec<String>> = OnceLock::new();
+/// the sample index of each `--group-tag` value
+static GROUPS: OnceLock<AHashMap<&'static [u8], u32>> = OnceLock::new();
+/// set by `--region`, `--targets` and `--exclude`
+static REGIONS: OnceLock<RegionFilter> = OnceLock::new();
+/// set by `--snp-vcf`
+static SNPS: OnceLock<SnpMask> = OnceLock::new();
 static DNAS: LazyLock<AHashMap<&'static [u8], &'static [u8]>> = LazyLock::new(|| {
     let ref_index_file = BufReader::new(File::open(&ARGS.reference_file_index).unwrap());
     let by_ascii: HashMap::<AsciiString, AsciiString> = from_read(ref_index_file).unwrap();
@@ -156,20 +480,83 @@ static DNAS: LazyLock<AHashMap<&'static [u8], &'static [u8]>> = LazyLock::new(||
     dnas
 });
 
+/// the `--group-tag`, CB with `--barcode-mode`
+fn group_tag() -> Option<&'static str> {
+    ARGS.group_tag.as_deref().or(ARGS.barcode_mode.then_some("CB"))
+}
+
+/// the `--umi-tag`, UB with `--barcode-mode`
+fn umi_tag() -> Option<&'static str> {
+    ARGS.umi_tag.as_deref().or(ARGS.barcode_mode.then_some("UB"))
+}
+
+/// the number of samples, one unless several are counted
+fn sample_count() -> usize {
+    SAMPLES.get().map_or(1, Vec::len)
+}
+
+fn is_control(dna: &[u8]) -> bool {
+    ARGS.unconverted_control.iter().chain(&ARGS.methylated_control).any(|c| c.as_bytes() == dna)
+}
+
 #[inline(never)]
-fn worker2(task: Task2<'static>) -> Vec<Position<'static>> {
-    let mut positions = Vec::new();
-    Vec::reserve(&mut positions, task.position_range.len());
+fn worker2(task: Task2<'static>) -> TaskOutput<'static> {
+    let mut mbias = MBias::default();
+    let mut stats = Stats::default();
+    let mut read_ids = ReadIdTable::default();
+    let mut read_names = ReadNames::default();
     let dna_name = task.dna_name;
+    let text = DNAS.get(dna_name).unwrap();
     // let ulen = DNAS.get(dna_name).unwrap().len();
     // eprintln!("{}, {}", str::from_utf8(dna_name).unwrap(), ulen);
-    fill_positions(&mut positions, DNAS.get(dna_name).unwrap(), dna_name, task.position_range.start, task.position_range.end);
+    let samples = sample_count();
+    let sparse = match ARGS.position_storage {
+        PositionStorage::Auto => task.bases < task.position_range.len() * samples,
+        PositionStorage::Dense => false,
+        PositionStorage::Sparse => true,
+    };
+    // by conversion, then sample. the positions of a sample are only
+    // allocated once a base of it is counted, so dense storage takes the
+    // reference range once per sample present in the task
+    let mut positions: Vec<Option<Positions>> = (0..ARGS.base_change.len() * samples).map(|_| None).collect();
 
-    for alignment in task.alignments {
+    for mut alignment in task.alignments() {
         debug_assert_eq!(alignment.dna, task.dna_name);
-        if !alignment.mapped || alignment.bases.is_empty() {
+        stats.records += 1;
+        if !alignment.mapped {
+            continue;
+        }
+        stats.mapped += 1;
+        if alignment.filtered_by_uniqueness() {
+            stats.uniqueness_filtered += 1;
+            continue;
+        }
+        if alignment.off_library {
+            stats.library_filtered += 1;
+            continue;
+        }
+        if alignment.ungrouped {
+            stats.ungrouped += 1;
+            continue;
+        }
+        if umi_tag().is_some() && alignment.umi.is_empty() {
+            stats.no_umi += 1;
+            continue;
+        }
+        if alignment.strand == 0 {
+            stats.no_strand += 1;
+            continue;
+        }
+        if alignment.bases.is_empty() {
+            continue;
+        }
+        if alignment.incompletely_converted(text) {
+            stats.offtarget_filtered += 1;
             continue;
         }
+        stats.ambiguous_bases += alignment.ambiguous_bases(text);
+        alignment.read_name_id = read_names.intern(&alignment);
+        read_ids.purge_before(alignment.location);
         // int firstPos = refPositions[0]->location;
         //         return targetPos - firstPos;
         for base in &alignment.bases {
@@ -177,64 +564,365 @@ fn worker2(task: Task2<'static>) -> Vec<Position<'static>> {
                 continue;
             }
 
-            let index = (alignment.location as usize) - task.position_range.start + (TryInto::<usize>::try_into(base.ref_pos).unwrap());
-            if index >= positions.len() {
+            let location = (alignment.location as usize) + (TryInto::<usize>::try_into(base.ref_pos).unwrap());
+            let index = base.conversion as usize * samples + alignment.sample as usize;
+            let positions = positions[index].get_or_insert_with(|| {
+                Positions::new(text, dna_name, task.position_range.clone(), sparse, base.conversion, alignment.sample)
+            });
+            let Some(position) = positions.get_mut(location) else {
                 continue;
-            }
-            // eprintln!("index: {}, positions: {}", index, positions.len());
-            let position = &mut positions[index as usize];
+            };
             assert_eq!(position.location, alignment.location + base.ref_pos);
 
             if position.strand.is_none() {
                 continue;
             }
+            if ARGS.mbias_report.is_some() {
+                mbias.add(base, &alignment);
+            }
+            if base.trimmed {
+                continue;
+            }
 
-            position.append_base(base, &alignment);
+            position.append_base(base, &alignment, &mut read_ids);
         }
     }
 
-    positions
+    let mut positions: Vec<_> = positions
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, positions)| {
+            let c = i / samples;
+            let mut positions = positions.map_or_else(Vec::new, Positions::into_vec);
+            if ARGS.merge_cpg_strands && b"CG".contains(&ARGS.base_change[c].0.0) {
+                let counted_c = |location: isize| {
+                    location > 0 && Position::from_reference(text, dna_name, location as usize, c as u8).strand.is_some()
+                };
+                merge_cpg_strands(&mut positions, c as u8, counted_c);
+            }
+            positions
+        })
+        .collect();
+    if ARGS.base_change.len() > 1 || samples > 1 {
+        positions.sort_by_key(|p| (p.location, p.conversion, p.sample));
+    }
+
+    for p in &positions {
+        stats.add_position(p);
+    }
+    stats.conflicts = read_ids.conflicts;
+    stats.collisions = read_names.collisions;
+
+    TaskOutput { dna: dna_name, positions, mbias, stats }
+}
+
+/// a dna and its alignment lines in each input
+type DnaSegment = (&'static [u8], Vec<&'static [u8]>);
+
+/// the alignment lines of each dna in every input, the dnas in order of
+/// their first input, collecting the aux `values` on the way
+fn dna_segments(files: &[&'static [u8]], mut values: Option<&mut AuxValues<'static>>) -> Result<Vec<DnaSegment>> {
+    let mut segments: Vec<DnaSegment> = Vec::new();
+    let mut index = AHashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        for (name, r) in scan_alignment_segments(file, values.as_deref_mut())? {
+            let j = *index.entry(name).or_insert_with(|| {
+                segments.push((name, vec![&b""[..]; files.len()]));
+                segments.len() - 1
+            });
+            segments[j].1[i] = &file[r];
+        }
+    }
+    Ok(segments)
 }
 
 fn main() -> Result<()> {
+    let start = std::time::Instant::now();
+    for (i, ((from, _), _)) in ARGS.base_change.iter().enumerate() {
+        if ARGS.base_change[..i].iter().any(|((f, _), _)| f == from) {
+            anyhow::bail!("--base-change given twice for {}", char::from(*from));
+        }
+    }
+    let from_c_or_g = |((from, _), _): &BaseChange| b"CG".contains(from);
+    if ARGS.split_contexts && !ARGS.base_change.iter().all(from_c_or_g) {
+        anyhow::bail!("--split-contexts requires every base change to be from C or G");
+    }
+    if (ARGS.cg_only || !ARGS.context.is_empty()) && !ARGS.base_change.iter().any(from_c_or_g) {
+        anyhow::bail!("--cg-only and --context require a base change from C or G");
+    }
+    if (ARGS.max_offtarget_conversions.is_some() || ARGS.max_offtarget_conversion_fraction.is_some())
+      && !ARGS.cg_only && ARGS.context.is_empty() {
+        anyhow::bail!("--max-offtarget-conversions and --max-offtarget-conversion-fraction need --cg-only or --context to define the off-target contexts");
+    }
+    if ARGS.merge_cpg_strands && !ARGS.base_change.iter().any(from_c_or_g) {
+        anyhow::bail!("--merge-cpg-strands requires a base change from C or G");
+    }
+    if ARGS.strand_inference.contains(&StrandSource::Flag) && ARGS.library_type == LibraryType::NonDirectional {
+        anyhow::bail!("--strand-inference flag needs a directional or pbat --library-type: reads of non-directional libraries come from any strand in either orientation");
+    }
     ThreadPoolBuilder::new().num_threads(ARGS.threads).build_global()?;
+    if ARGS.error_rate == Some(ErrorRate::Control) {
+        let controls = if ARGS.test_count == TestCount::Converted { &ARGS.methylated_control } else { &ARGS.unconverted_control };
+        if controls.is_empty() {
+            anyhow::bail!("--error-rate control requires a {} control", if ARGS.test_count == TestCount::Converted { "--methylated-control" } else { "--unconverted-control" });
+        }
+    }
+    if !(0.0..1.0).contains(&ARGS.overdispersion) {
+        anyhow::bail!("--overdispersion must be in [0, 1)");
+    }
+    for control in ARGS.unconverted_control.iter().chain(&ARGS.methylated_control) {
+        if !DNAS.contains_key(control.as_bytes()) {
+            anyhow::bail!("control contig {} is not in the reference", control);
+        }
+    }
 
-    let (tx, rx) = mpsc::channel();
-    let dna_align_segments: Vec<(&[u8], std::ops::Range<usize>)> = scan_alignment_segments(&ALIGN_FILE);
+    if !ARGS.region.is_empty() || !ARGS.targets.is_empty() || !ARGS.exclude.is_empty() {
+        let _ = REGIONS.set(RegionFilter::new(&ARGS.region, &ARGS.targets, &ARGS.exclude)?);
+    }
 
+    if let Some(vcf) = &ARGS.snp_vcf {
+        let snps = SnpMask::from_vcf(vcf, ARGS.snp_transitions_only, &ARGS.base_change)?;
+        eprintln!("{} variant positions masked", snps.len());
+        let _ = SNPS.set(snps);
+    }
+
+    let names = if ARGS.sample_names.is_empty() {
+        ARGS.alignment_files.iter().map(|f| f.file_stem().unwrap_or_default().to_string_lossy().into_owned()).collect()
+    } else if ARGS.sample_names.len() == ARGS.alignment_files.len() {
+        ARGS.sample_names.clone()
+    } else {
+        anyhow::bail!("{} --sample-names for {} --alignments", ARGS.sample_names.len(), ARGS.alignment_files.len());
+    };
+    if ARGS.alignment_files.len() > 1 {
+        if group_tag().is_some() {
+            anyhow::bail!("--group-tag and --barcode-mode need a single --alignments file");
+        }
+        SAMPLES.set(names).unwrap();
+    }
+
+    // the --group-values are read before the alignments are sorted or spooled
+    if ARGS.group_values.is_some() && group_tag().is_none() {
+        anyhow::bail!("--group-values needs --group-tag or --barcode-mode");
+    }
+    // a UMI only identifies a molecule within a cell
+    if ARGS.umi_tag.is_some() && group_tag().is_none() {
+        anyhow::bail!("--umi-tag needs --group-tag or --barcode-mode");
+    }
+    let group_values = match (&ARGS.group_values, group_tag()) {
+        (Some(path), Some(tag)) => {
+            let text = std::fs::read(path).with_context(|| format!("cannot read --group-values {}", path.display()))?;
+            let text: &'static [u8] = Box::leak(text.into_boxed_slice());
+            let mut seen = AHashSet::new();
+            let values: Vec<_> = text.split(|&b| b == b'\n')
+                .filter_map(|line| line.split(|&b| b == b'\t' || b == b'\r').next())
+                .filter(|value| !value.is_empty() && seen.insert(*value))
+                .collect();
+            if values.is_empty() {
+                anyhow::bail!("no {} values in --group-values {}", tag, path.display());
+            }
+            Some(values)
+        }
+        _ => None,
+    };
+
+    let tmp_dir = ARGS.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+    if ARGS.alignment_files.iter().filter(|f| f.as_os_str() == "-").count() > 1 {
+        anyhow::bail!("standard input ('-') given more than once in --alignments");
+    }
+    // sorted copies and standard input spooled to a file, removed when dropped
+    let mut prepared = Vec::new();
+    let mut inputs = ARGS.alignment_files.clone();
+    for input in &mut inputs {
+        let copy = if ARGS.sort_input {
+            sort::external_sort(input, &tmp_dir, ARGS.max_memory.unwrap_or(1 << 30))?
+        } else if input.as_os_str() == "-" {
+            sort::spool_stdin(&tmp_dir)?
+        } else {
+            continue;
+        };
+        *input = copy.path().to_path_buf();
+        prepared.push(copy);
+    }
+    ALIGN_PATHS.set(inputs).unwrap();
+    // the mappings stay valid once the files are removed
+    LazyLock::force(&ALIGN_FILES);
+    drop(prepared);
+
+    // the --group-tag values are collected while the alignments are scanned
+    let mut aux_values = group_tag().filter(|_| ARGS.group_values.is_none()).map(|tag| AuxValues::new(tag.as_bytes()));
+    let dna_align_segments = dna_segments(&ALIGN_FILES, aux_values.as_mut())?;
+    if let Some(tag) = group_tag() {
+        let values = match group_values {
+            Some(values) => values,
+            None => aux_values.unwrap().values,
+        };
+        if values.is_empty() {
+            anyhow::bail!("no {} values in the alignments", tag);
+        }
+        if u32::try_from(values.len()).is_err() {
+            anyhow::bail!("{} {} values, at most {} groups are supported", values.len(), tag, u32::MAX);
+        }
+        eprintln!("{} {} groups", values.len(), tag);
+        SAMPLES.set(values.iter().map(|v| String::from_utf8_lossy(v).into_owned()).collect()).unwrap();
+        GROUPS.set(values.into_iter().enumerate().map(|(i, v)| (v, i as u32)).collect()).unwrap();
+    }
+
+    // tasks are issued in order and their results written in order; at most
+    // `task_window()` of them are in flight, which bounds both the channel
+    // and the reorder buffer below
+    let window = Arc::new(TaskWindow::new(task_window()));
+    let (tx, rx) = mpsc::sync_channel(task_window());
+    let sizes = block_sizes(ALIGN_FILES[0], sample_count());
+    let skipped_lines = |lines: &[&[u8]]| {
+        let mut skipped = Stats::default();
+        for lines in lines {
+            skipped.merge(&skipped_records(lines));
+        }
+        skipped
+    };
+
+    // the segments are split into tasks in parallel, then issued in order.
+    // the records of unselected dnas and tasks are only counted for the
+    // summary
+    let (tasks, skipped): (Vec<Vec<Task2>>, Vec<Stats>) = dna_align_segments
+        .par_iter()
+        .map(|(name, lines)| {
+            if !DNAS.contains_key(name) || !REGIONS.get().is_none_or(|r| r.contains_dna(name)) {
+                return (Vec::new(), skipped_lines(lines));
+            }
+            let mut skipped = Stats::default();
+            let tasks = TaskIter2::new(lines.clone(), sizes)
+                .filter(|task| {
+                    let selected = REGIONS.get().is_none_or(|r| r.overlaps(task.dna_name, &task.position_range));
+                    if !selected {
+                        skipped.merge(&skipped_lines(&task.lines));
+                    }
+                    selected
+                })
+                .collect();
+            (tasks, skipped)
+        })
+        .unzip();
+
+    let error_rate = match ARGS.error_rate {
+        Some(ErrorRate::Fixed(rate)) => Some(rate),
+        Some(ErrorRate::Control) => {
+            // the control contigs are counted once ahead, so that every
+            // position gets a p-value and the table keeps reference order
+            let controls: Vec<_> = tasks
+                .par_iter()
+                .flatten()
+                .filter(|task| is_control(task.dna_name))
+                .map(|task| (task.dna_name, worker2(task.clone()).stats))
+                .collect();
+            let mut summary = Summary::default();
+            for (dna, stats) in &controls {
+                summary.add(dna, DNAS[dna].len(), stats);
+            }
+            let Some(rate) = summary.control_error_rate() else {
+                anyhow::bail!("no counted bases on the control contigs to estimate the error rate from");
+            };
+            eprintln!("error rate {:.6} estimated from the control contigs", rate);
+            Some(rate)
+        }
+        None => None,
+    };
+
+    let producer_window = window.clone();
     std::thread::spawn(move || {
-        let tx = tx.clone();
-        dna_align_segments
-            .par_iter()
-            .flat_map(|(_, r)| TaskIter2::new(&ALIGN_FILE[r.start..r.end]).par_bridge())
-            .map(worker2)
-            .for_each(|positions| { tx.send(Some(positions)).unwrap(); });
-        
-        tx.send(None).unwrap();
+        issue_tasks(tasks.into_iter().flatten(), &producer_window, tx, worker2);
     });
 
-    let mut output = std::io::BufWriter::with_capacity(1 * 1024 * 1024, File::create(&ARGS.output_name)?);
-
-    writeln!(output, "ref\tpos\tstrand\tconvertedBaseQualities\tconvertedBaseCount\tunconvertedBaseQualities\tunconvertedBaseCount")?;
+    let mut output = TableWriter::new(&ARGS.output_name, error_rate)?;
+    let mut aggregator = match &ARGS.aggregate_output {
+        Some(path) => Some(Aggregator::new(path, ARGS.aggregate_bed.as_deref(), ARGS.window, |dna| DNAS.get(dna).map(|text| text.len()))?),
+        None => None,
+    };
+    let mut matrix = match &ARGS.matrix_output {
+        Some(prefix) => {
+            let stem = || vec![ARGS.alignment_files[0].file_stem().unwrap_or_default().to_string_lossy().into_owned()];
+            Some(MatrixWriter::new(prefix, &SAMPLES.get().cloned().unwrap_or_else(stem))?)
+        }
+        None => None,
+    };
 
-    loop {
-        let res = rx.recv()?;
-        match res {
-            TaskResult::Some(positions) => {
-                for p in positions {
-                    if p.converted_qualities.is_empty() && p.unconverted_qualities.is_empty() {
-                        continue;
+    let mut mbias = MBias::default();
+    let mut summary = Summary::default();
+    for stats in &skipped {
+        summary.add_skipped(stats);
+    }
+    let mut pending = BTreeMap::new();
+    let mut next_task = 0;
+    // the channel closes once every issued task has sent its result
+    while let Ok((i, task_output)) = rx.recv() {
+        pending.insert(i, task_output);
+        while let Some(task_output) = pending.remove(&next_task) {
+            mbias.merge(&task_output.mbias);
+            summary.add(task_output.dna, DNAS[task_output.dna].len(), &task_output.stats);
+            if !(ARGS.exclude_controls && is_control(task_output.dna)) {
+                for p in task_output.positions {
+                    if is_reported(&p) {
+                        if let Some(aggregator) = &mut aggregator {
+                            aggregator.add(&p)?;
+                        }
+                        if let Some(matrix) = &mut matrix {
+                            matrix.add(&p)?;
+                        }
                     }
-                    let len1 = p.converted_qualities.len();
-                    let len2 = p.unconverted_qualities.len();
-                    writeln!(output, "{}\t{}\t{}\t{}\t{}\t{}\t{}", str::from_utf8(p.dna).unwrap(), p.location, char::from(p.strand.unwrap_or(b'?')), String::from_utf8(p.converted_qualities).unwrap(), len1, String::from_utf8(p.unconverted_qualities).unwrap(), len2)?;
+                    output.write(p)?;
                 }
             }
-            TaskResult::None => {
-                cold_path();
-                break;
+            window.release();
+            next_task += 1;
+        }
+    }
+
+    output.finish()?;
+    if let Some(aggregator) = aggregator {
+        aggregator.finish()?;
+    }
+    if let Some(matrix) = matrix {
+        matrix.finish()?;
+    }
+
+    let stats = summary.stats();
+    eprintln!("{} conflicting bases of the same read", stats.conflicts);
+    if ARGS.read_identity == ReadIdentity::Name {
+        eprintln!("{} read name hash collisions detected", stats.collisions);
+    }
+    if let Some(tag) = group_tag() {
+        eprintln!("{} mapped reads without a {} tag not counted", stats.ungrouped, tag);
+    }
+    if let Some(tag) = umi_tag() {
+        eprintln!("{} mapped reads without a {} tag not counted", stats.no_umi, tag);
+    }
+    eprintln!("{} aligned bases on N or IUPAC ambiguity codes of the reference not counted", stats.ambiguous_bases);
+    if ARGS.max_offtarget_conversions.is_some() || ARGS.max_offtarget_conversion_fraction.is_some() {
+        eprintln!("{} reads dropped by the off-target conversion filter", stats.offtarget_filtered);
+    }
+    for control in summary.controls() {
+        let kind = if control.methylated { "methylated" } else { "unconverted" };
+        for (context, rate) in &control.contexts {
+            let (name, r, ci) = if control.methylated {
+                ("non-conversion", rate.non_conversion_rate, rate.non_conversion_rate_ci)
+            } else {
+                ("conversion", rate.conversion_rate, rate.conversion_rate_ci)
+            };
+            if let (Some(r), Some([lower, upper])) = (r, ci) {
+                eprintln!("{} ({} control) {} {} rate {:.4} (95% CI {:.4}-{:.4}) of {} bases", control.name, kind, context, name, r, lower, upper, rate.converted + rate.unconverted);
             }
         }
+        if control.total.converted + control.total.unconverted == 0 {
+            eprintln!("{} ({} control) has no counted bases", control.name, kind);
+        }
+    }
+    if let Some(summary_name) = &ARGS.summary {
+        summary.write(summary_name, start.elapsed())?;
+    }
+
+    if let Some(mbias_name) = &ARGS.mbias_report {
+        let mut mbias_output = std::io::BufWriter::new(File::create(mbias_name)?);
+        mbias.write(&mut mbias_output)?;
     }
 
     Ok(())
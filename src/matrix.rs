@@ -0,0 +1,135 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::context::Context;
+use crate::output::{base_change_label, multiple_base_changes};
+use crate::position::Position;
+
+const MATRIX_HEADER: &str = "%%MatrixMarket matrix coordinate integer general\n";
+/// room for "rows columns entries", filled in by `finish`
+const SIZE_WIDTH: usize = 64;
+
+/// prefix -> prefix.converted.mtx
+fn prefixed(prefix: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(prefix.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn create_matrix(path: &Path) -> Result<BufWriter<File>> {
+    let mut output = BufWriter::with_capacity(1024 * 1024, File::create(path)?);
+    write!(output, "{}{:width$}", MATRIX_HEADER, "", width = SIZE_WIDTH)?;
+    writeln!(output)?;
+    Ok(output)
+}
+
+/// writes the counts of each sample (e.g. cell barcode) at the written sites
+/// as two sparse sample x site MatrixMarket matrices of the nonzero counts,
+/// prefix.converted.mtx and prefix.unconverted.mtx. row i is line i of
+/// prefix.samples.tsv, column j line j of prefix.sites.tsv (ref, pos,
+/// strand, context and baseChange with several `--base-change`s).
+pub struct MatrixWriter {
+    converted: BufWriter<File>,
+    unconverted: BufWriter<File>,
+    sites: BufWriter<File>,
+    samples: usize,
+    /// (dna, location, conversion) of the last column
+    site: Option<(&'static [u8], isize, u8)>,
+    columns: usize,
+    /// the entries written to the converted and unconverted matrix
+    entries: [usize; 2],
+}
+
+impl MatrixWriter {
+    pub fn new(prefix: &Path, samples: &[String]) -> Result<Self> {
+        let mut names = BufWriter::new(File::create(prefixed(prefix, ".samples.tsv"))?);
+        for sample in samples {
+            writeln!(names, "{}", sample)?;
+        }
+        names.flush()?;
+        Ok(Self {
+            converted: create_matrix(&prefixed(prefix, ".converted.mtx"))?,
+            unconverted: create_matrix(&prefixed(prefix, ".unconverted.mtx"))?,
+            sites: BufWriter::new(File::create(prefixed(prefix, ".sites.tsv"))?),
+            samples: samples.len(),
+            site: None,
+            columns: 0,
+            entries: [0, 0],
+        })
+    }
+
+    /// adds the counts of a sample; positions come in table order, the
+    /// samples of a site one after another
+    pub fn add(&mut self, p: &Position<'static>) -> Result<()> {
+        let site = (p.dna, p.location, p.conversion);
+        if self.site != Some(site) {
+            self.site = Some(site);
+            self.columns += 1;
+            let context = p.context.map_or(".", Context::as_str);
+            write!(self.sites, "{}\t{}\t{}\t{}", str::from_utf8(p.dna).unwrap(), p.location, char::from(p.strand.unwrap_or(b'?')), context)?;
+            if multiple_base_changes() {
+                write!(self.sites, "\t{}", base_change_label(p.conversion))?;
+            }
+            writeln!(self.sites)?;
+        }
+        let row = p.sample as usize + 1;
+        let outputs = [&mut self.converted, &mut self.unconverted];
+        for ((output, entries), count) in outputs.into_iter().zip(&mut self.entries).zip([p.converted.count, p.unconverted.count]) {
+            if count > 0 {
+                writeln!(output, "{}\t{}\t{}", row, self.columns, count)?;
+                *entries += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        for (output, entries) in [&mut self.converted, &mut self.unconverted].into_iter().zip(self.entries) {
+            let size = format!("{} {} {}", self.samples, self.columns, entries);
+            output.seek(SeekFrom::Start(MATRIX_HEADER.len() as u64))?;
+            write!(output, "{:width$}", size, width = SIZE_WIDTH)?;
+            output.flush()?;
+        }
+        self.sites.flush()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_matrix_writer() {
+    let dir = std::env::temp_dir().join(format!("hisat-3n-table.{}.matrix", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let prefix = dir.join("m");
+    let mut matrix = MatrixWriter::new(&prefix, &["AAC".to_owned(), "GGT".to_owned()]).unwrap();
+    // the samples of a site come one after another and share its column,
+    // zero counts are left out
+    for (location, conversion, sample, converted, unconverted) in [(3, 0, 0, 2, 1), (3, 0, 1, 0, 4), (3, 1, 1, 1, 0), (9, 0, 0, 5, 5)] {
+        let mut p = Position::new(b"chr1", location);
+        p.strand = Some(b'+');
+        p.conversion = conversion;
+        p.sample = sample;
+        (0..converted).for_each(|_| p.converted.push(b'I'));
+        (0..unconverted).for_each(|_| p.unconverted.push(b'I'));
+        matrix.add(&p).unwrap();
+    }
+    matrix.finish().unwrap();
+
+    let read = |suffix| std::fs::read_to_string(prefixed(&prefix, suffix)).unwrap();
+    let converted = read(".converted.mtx");
+    // the size line is rewritten in place, padded to its reserved width
+    let (header, entries) = converted.split_at(MATRIX_HEADER.len() + SIZE_WIDTH + 1);
+    assert_eq!(header, format!("{}{:width$}\n", MATRIX_HEADER, "2 3 3", width = SIZE_WIDTH));
+    // rows and columns are 1-based
+    assert_eq!(entries, "1\t1\t2\n2\t2\t1\n1\t3\t5\n");
+    let unconverted = read(".unconverted.mtx");
+    let (header, entries) = unconverted.split_at(MATRIX_HEADER.len() + SIZE_WIDTH + 1);
+    assert_eq!(header.trim_end(), format!("{}2 3 3", MATRIX_HEADER));
+    assert_eq!(entries, "1\t1\t1\n2\t1\t4\n1\t3\t5\n");
+    assert_eq!(read(".samples.tsv"), "AAC\nGGT\n");
+    assert_eq!(read(".sites.tsv"), "chr1\t3\t+\t.\tC>T\nchr1\t3\t+\t.\tG>A\nchr1\t9\t+\t.\tC>T\n");
+    std::fs::remove_dir_all(dir).unwrap();
+}
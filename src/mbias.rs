@@ -0,0 +1,112 @@
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::alignment::{Alignment, PosQuality};
+use crate::output::{base_change_label, multiple_base_changes};
+
+/// conversion counts by read cycle, kept separately for R1/R2, for the
+/// converted strand (`YZ` tag) of the read and for each `--base-change`.
+#[derive(Default)]
+pub struct MBias {
+    /// index: conversion * 4 + mate * 2 + strand, then read cycle ->
+    /// [converted, unconverted]
+    counts: Vec<Vec<[u64; 2]>>,
+}
+
+const MATES: [&str; 2] = ["R1", "R2"];
+const STRANDS: [char; 2] = ['+', '-'];
+
+impl MBias {
+    pub fn add(&mut self, base: &PosQuality, a: &Alignment) {
+        let strand = match a.strand {
+            b'+' => 0,
+            b'-' => 1,
+            _ => return,
+        };
+        let mate = if a.flag & 0x80 != 0 { 1 } else { 0 };
+        let index = base.conversion as usize * 4 + mate * 2 + strand;
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, Vec::new());
+        }
+        let cycles = &mut self.counts[index];
+        let cycle = base.cycle as usize;
+        if cycles.len() <= cycle {
+            cycles.resize(cycle + 1, [0, 0]);
+        }
+        cycles[cycle][if base.converted { 0 } else { 1 }] += 1;
+    }
+
+    pub fn merge(&mut self, other: &MBias) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), Vec::new());
+        }
+        for (mine, theirs) in self.counts.iter_mut().zip(other.counts.iter()) {
+            if mine.len() < theirs.len() {
+                mine.resize(theirs.len(), [0, 0]);
+            }
+            for (m, t) in mine.iter_mut().zip(theirs.iter()) {
+                m[0] += t[0];
+                m[1] += t[1];
+            }
+        }
+    }
+
+    pub fn write(&self, output: &mut impl Write) -> Result<()> {
+        let multiple = multiple_base_changes();
+        if multiple {
+            write!(output, "baseChange\t")?;
+        }
+        writeln!(output, "read\tstrand\tcycle\tconvertedBaseCount\tunconvertedBaseCount\tconversionRate")?;
+        for (i, cycles) in self.counts.iter().enumerate() {
+            for (cycle, [converted, unconverted]) in cycles.iter().enumerate() {
+                let total = converted + unconverted;
+                if total == 0 {
+                    continue;
+                }
+                let rate = *converted as f64 / total as f64;
+                if multiple {
+                    write!(output, "{}\t", base_change_label((i / 4) as u8))?;
+                }
+                writeln!(output, "{}\t{}\t{}\t{}\t{}\t{:.6}", MATES[i / 2 % 2], STRANDS[i % 2], cycle + 1, converted, unconverted, rate)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+/// a base of `cycle` counted for `conversion` in `line`
+fn add_base(mbias: &mut MBias, line: &[u8], conversion: u8, cycle: u32, converted: bool) {
+    let a = Alignment::from_file(line).unwrap();
+    let base = PosQuality { conversion, cycle, converted, ..PosQuality::new(0) };
+    mbias.add(&base, &a);
+}
+
+#[test]
+fn test_mbias() {
+    let r1 = b"r1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\tYZ:A:+";
+    let r2 = b"r1\t144\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\tYZ:A:-";
+    let no_strand = b"r2\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII";
+    let mut first = MBias::default();
+    add_base(&mut first, r1, 0, 0, true);
+    add_base(&mut first, r1, 0, 0, false);
+    add_base(&mut first, r2, 1, 2, true);
+    add_base(&mut first, no_strand, 0, 0, true);
+    let mut second = MBias::default();
+    add_base(&mut second, r1, 0, 0, true);
+    add_base(&mut second, r1, 0, 1, false);
+
+    // tasks are merged into the report in any order and length
+    let mut mbias = MBias::default();
+    mbias.merge(&second);
+    mbias.merge(&first);
+    let mut report = Vec::new();
+    mbias.write(&mut report).unwrap();
+    assert_eq!(String::from_utf8(report).unwrap(), concat!(
+        "baseChange\tread\tstrand\tcycle\tconvertedBaseCount\tunconvertedBaseCount\tconversionRate\n",
+        "C>T\tR1\t+\t1\t2\t1\t0.666667\n",
+        "C>T\tR1\t+\t2\t0\t1\t0.000000\n",
+        "G>A\tR2\t-\t3\t1\t0\t1.000000\n",
+    ));
+}
@@ -0,0 +1,308 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use ahash::AHashMap;
+use anyhow::Result;
+
+use crate::context::Context;
+use crate::position::{BaseCalls, Position};
+use crate::pvalue::{upper_tail, TestCount};
+use crate::ARGS;
+
+const HEADER: &str = "ref\tpos\tstrand\tconvertedBaseQualities\tconvertedBaseCount\tunconvertedBaseQualities\tunconvertedBaseCount";
+const COUNTS_ONLY_HEADER: &str = "ref\tpos\tstrand\tconvertedBaseMeanQuality\tconvertedBaseCount\tunconvertedBaseMeanQuality\tunconvertedBaseCount";
+
+const WIDE_HEADER: &str = "ref\tpos\tstrand";
+
+/// the table layout with several samples
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SampleFormat {
+    /// one row per position and sample, with a sample column
+    Long,
+    /// one row per position, with the counts of every sample
+    Wide,
+    /// one table per sample, e.g. out.sampleA.tsv
+    Split,
+}
+
+/// writes the 3n table, either to one file or, with `--split-contexts`,
+/// to one file per counted context, and with `--sample-format split` to
+/// one file (or one per context) per sample.
+pub struct TableWriter {
+    /// a single output, or one per `Context` in `Context::ALL` order, for
+    /// each split sample
+    outputs: Vec<Option<BufWriter<File>>>,
+    /// the `--error-rate`, or its estimate for `control`, for the pValue
+    /// column
+    error_rate: Option<f64>,
+    /// the samples of the wide row being collected
+    row: Vec<Position<'static>>,
+    /// whether the samples are written to separate tables
+    split: bool,
+}
+
+/// out.tsv -> out.CG.tsv
+fn suffixed_file_name(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(suffix);
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+/// the table of each sample with `--sample-format split`, e.g. out.sampleA.tsv.
+/// characters of a label other than letters, digits and `._+-` are replaced
+/// by '_', so that a label like "../x" stays in the directory of `path`.
+fn split_paths(path: &Path, samples: &[String]) -> Result<Vec<PathBuf>> {
+    let mut seen = AHashMap::new();
+    let mut paths = Vec::new();
+    for sample in samples {
+        let label: String = sample
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "._+-".contains(c) { c } else { '_' })
+            .collect();
+        if let Some(other) = seen.insert(label.clone(), sample) {
+            anyhow::bail!("samples {} and {} would both be written to the {} table", other, sample, label);
+        }
+        paths.push(suffixed_file_name(path, &label));
+    }
+    Ok(paths)
+}
+
+/// whether a `baseChange` column tells the conversions apart
+pub fn multiple_base_changes() -> bool {
+    ARGS.base_change.len() > 1
+}
+
+/// the `conversion`th `--base-change` as e.g. "C>T"
+pub fn base_change_label(conversion: u8) -> String {
+    let ((from, _), (to, _)) = ARGS.base_change[conversion as usize];
+    format!("{}>{}", char::from(from), char::from(to))
+}
+
+/// the sample labels with several samples
+fn samples() -> Option<&'static [String]> {
+    crate::SAMPLES.get().map(Vec::as_slice)
+}
+
+fn wide() -> bool {
+    samples().is_some() && ARGS.sample_format == SampleFormat::Wide
+}
+
+/// whether the samples are written to separate tables
+fn split_samples() -> bool {
+    samples().is_some() && ARGS.sample_format == SampleFormat::Split
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>> {
+    let mut output = BufWriter::with_capacity(1024 * 1024, File::create(path)?);
+    if wide() {
+        write!(output, "{}", WIDE_HEADER)?;
+        if ARGS.context_column {
+            write!(output, "\tcontext")?;
+        }
+        if multiple_base_changes() {
+            write!(output, "\tbaseChange")?;
+        }
+        for sample in samples().unwrap() {
+            write!(output, "\t{0}.convertedBaseCount\t{0}.unconvertedBaseCount", sample)?;
+            if ARGS.error_rate.is_some() {
+                write!(output, "\t{}.pValue", sample)?;
+            }
+        }
+        writeln!(output)?;
+        return Ok(output);
+    }
+    write!(output, "{}", if ARGS.counts_only { COUNTS_ONLY_HEADER } else { HEADER })?;
+    if ARGS.context_column {
+        write!(output, "\tcontext")?;
+    }
+    if multiple_base_changes() {
+        write!(output, "\tbaseChange")?;
+    }
+    if samples().is_some() && !split_samples() {
+        write!(output, "\tsample")?;
+    }
+    if ARGS.error_rate.is_some() {
+        write!(output, "\tpValue")?;
+    }
+    writeln!(output)?;
+    Ok(output)
+}
+
+/// whether `p` has enough counted bases to be written, see `--min-coverage`
+pub fn is_reported(p: &Position) -> bool {
+    let coverage = p.converted.count + p.unconverted.count;
+    coverage > 0 && coverage >= ARGS.min_coverage
+}
+
+/// the quality string, or the mean phred score with `--counts-only`
+fn qualities(calls: &BaseCalls, counts_only: bool) -> String {
+    if counts_only {
+        calls.mean_quality().map_or_else(|| ".".to_owned(), |q| format!("{:.1}", q))
+    } else {
+        String::from_utf8_lossy(&calls.qualities).into_owned()
+    }
+}
+
+impl TableWriter {
+    pub fn new(path: &Path, error_rate: Option<f64>) -> Result<Self> {
+        Self::with_split_samples(path, error_rate, samples().filter(|_| split_samples()))
+    }
+
+    /// a writer with one table per sample of `split`
+    fn with_split_samples(path: &Path, error_rate: Option<f64>, split: Option<&[String]>) -> Result<Self> {
+        let paths = match split {
+            Some(samples) => split_paths(path, samples)?,
+            None => vec![path.to_owned()],
+        };
+        let mut outputs = Vec::new();
+        for path in paths {
+            if ARGS.split_contexts {
+                for c in Context::ALL {
+                    outputs.push(if c.is_counted() { Some(create(&suffixed_file_name(&path, c.as_str()))?) } else { None });
+                }
+            } else {
+                outputs.push(Some(create(&path)?));
+            }
+        }
+        Ok(Self { outputs, error_rate, row: Vec::new(), split: split.is_some() })
+    }
+
+    /// the p-value column of `p`, "." without an error rate
+    fn p_value(&self, p: &Position) -> String {
+        let Some(rate) = self.error_rate else {
+            return ".".to_owned();
+        };
+        let tested = if ARGS.test_count == TestCount::Converted { p.converted.count } else { p.unconverted.count };
+        format!("{:.4e}", upper_tail(tested, p.converted.count + p.unconverted.count, rate, ARGS.overdispersion))
+    }
+
+    fn output(&mut self, context: Option<Context>, sample: u32) -> Option<&mut BufWriter<File>> {
+        let (index, per_sample) = if ARGS.split_contexts {
+            (Context::ALL.iter().position(|&c| Some(c) == context).unwrap(), Context::ALL.len())
+        } else {
+            (0, 1)
+        };
+        let sample = if self.split { sample as usize } else { 0 };
+        self.outputs[sample * per_sample + index].as_mut()
+    }
+
+    pub fn write(&mut self, p: Position<'static>) -> Result<()> {
+        if wide() {
+            if self.row.first().is_some_and(|r| (r.dna, r.location, r.conversion) != (p.dna, p.location, p.conversion)) {
+                self.write_row()?;
+            }
+            self.row.push(p);
+            return Ok(());
+        }
+        if !is_reported(&p) {
+            return Ok(());
+        }
+        let p_value = ARGS.error_rate.is_some().then(|| self.p_value(&p));
+        let sample_column = samples().filter(|_| !self.split);
+        let Some(output) = self.output(p.context, p.sample) else {
+            return Ok(());
+        };
+        write!(output, "{}\t{}\t{}\t{}\t{}\t{}\t{}", str::from_utf8(p.dna).unwrap(), p.location, char::from(p.strand.unwrap_or(b'?')), qualities(&p.converted, ARGS.counts_only), p.converted.count, qualities(&p.unconverted, ARGS.counts_only), p.unconverted.count)?;
+        if ARGS.context_column {
+            write!(output, "\t{}", p.context.map_or(".", Context::as_str))?;
+        }
+        if multiple_base_changes() {
+            write!(output, "\t{}", base_change_label(p.conversion))?;
+        }
+        if let Some(samples) = sample_column {
+            write!(output, "\t{}", samples[p.sample as usize])?;
+        }
+        if let Some(p_value) = p_value {
+            write!(output, "\t{}", p_value)?;
+        }
+        writeln!(output)?;
+        Ok(())
+    }
+
+    /// writes the samples of one position and conversion as a wide row, if
+    /// any of them is reported
+    fn write_row(&mut self) -> Result<()> {
+        let row = std::mem::take(&mut self.row);
+        if !row.iter().any(is_reported) {
+            return Ok(());
+        }
+        let mut line = String::new();
+        let p = &row[0];
+        line.push_str(&format!("{}\t{}\t{}", str::from_utf8(p.dna).unwrap(), p.location, char::from(p.strand.unwrap_or(b'?'))));
+        if ARGS.context_column {
+            line.push_str(&format!("\t{}", p.context.map_or(".", Context::as_str)));
+        }
+        if multiple_base_changes() {
+            line.push_str(&format!("\t{}", base_change_label(p.conversion)));
+        }
+        let mut row = row.iter().peekable();
+        for sample in 0..samples().unwrap().len() {
+            match row.next_if(|p| p.sample as usize == sample) {
+                Some(p) => {
+                    line.push_str(&format!("\t{}\t{}", p.converted.count, p.unconverted.count));
+                    if ARGS.error_rate.is_some() {
+                        let p_value = if p.converted.is_empty() && p.unconverted.is_empty() { ".".to_owned() } else { self.p_value(p) };
+                        line.push_str(&format!("\t{}", p_value));
+                    }
+                }
+                None => line.push_str(if ARGS.error_rate.is_some() { "\t0\t0\t." } else { "\t0\t0" }),
+            }
+        }
+        if let Some(output) = self.output(p.context, 0) {
+            writeln!(output, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if !self.row.is_empty() {
+            self.write_row()?;
+        }
+        for mut output in self.outputs.into_iter().flatten() {
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_split_samples() {
+    let dir = std::env::temp_dir().join(format!("hisat-3n-table.{}.split", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let samples = ["../A".to_owned(), "B".to_owned()];
+    let paths = split_paths(&dir.join("out.tsv"), &samples).unwrap();
+    assert_eq!(paths, [dir.join("out..._A.tsv"), dir.join("out.B.tsv")]);
+    assert!(split_paths(&dir.join("out.tsv"), &["a/b".to_owned(), "a_b".to_owned()]).is_err());
+
+    // each position goes to the table of its sample, without a sample column
+    let mut writer = TableWriter::with_split_samples(&dir.join("out.tsv"), None, Some(&samples)).unwrap();
+    for (location, sample) in [(3, 1), (5, 0), (7, 1)] {
+        let mut p = Position::new(b"chr1", location);
+        p.strand = Some(b'+');
+        p.sample = sample;
+        p.converted.push(b'I');
+        writer.write(p).unwrap();
+    }
+    writer.finish().unwrap();
+    let table = |path| std::fs::read_to_string(path).unwrap().lines().skip(1).map(str::to_owned).collect::<Vec<_>>();
+    assert_eq!(table(&paths[0]), ["chr1\t5\t+\tI\t1\t\t0\tC>T"]);
+    assert_eq!(table(&paths[1]), ["chr1\t3\t+\tI\t1\t\t0\tC>T", "chr1\t7\t+\tI\t1\t\t0\tC>T"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_counts_only_qualities() {
+    let mut calls = BaseCalls::default();
+    assert_eq!(qualities(&calls, true), ".");
+    assert_eq!(qualities(&calls, false), "");
+    calls.push(b'5');
+    calls.push(b'I');
+    assert_eq!(qualities(&calls, true), "30.0");
+    assert_eq!(qualities(&calls, false), "5I");
+}
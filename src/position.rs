@@ -1,22 +1,23 @@
-use std::{collections::BTreeMap, hint::cold_path};
+use std::ops::Range;
+
+use ahash::AHashMap;
 
 use crate::{
     ARGS,
     alignment::{Alignment, PosQuality},
+    context::Context,
 };
 
 #[derive(Default, Debug, Clone)]
 pub struct UniqueID {
-    pub read_name_id: u64,
     pub converted: bool,
     pub quality: u8,
     pub removed: bool,
 }
 
 impl UniqueID {
-    fn new(read_name_id: u64, converted: bool, quality: u8) -> Self {
+    fn new(converted: bool, quality: u8) -> Self {
         Self {
-            read_name_id,
             converted,
             quality,
             removed: false,
@@ -24,13 +25,123 @@ impl UniqueID {
     }
 }
 
+/// how to resolve bases of one read with different conversion status at a
+/// position, e.g. from overlapping mates
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// count neither base
+    DropBoth,
+    /// count the base seen first
+    KeepFirst,
+    /// count the base with the higher quality, the first one on ties
+    KeepHigherQuality,
+}
+
+const MIN_PURGE_LEN: usize = 1 << 16;
+
+type ReadIdKey = (u64, u32, u8, isize);
+
+/// the read name ids counted in a task, keyed by (read name id, sample,
+/// conversion, location): the bases of a read for different `--base-change`s
+/// are counted at different `Position`s and never conflict.
+/// alignments are sorted, so no later alignment reaches the locations before
+/// the current one and their entries can be purged.
+#[derive(Default)]
+pub struct ReadIdTable {
+    ids: AHashMap<ReadIdKey, UniqueID>,
+    purge_len: usize,
+    /// bases of a read conflicting with an earlier base of the same read
+    pub conflicts: usize,
+}
+
+impl ReadIdTable {
+    /// memory of an entry, with the control byte of the hash map
+    pub const ENTRY_BYTES: usize = size_of::<(ReadIdKey, UniqueID)>() + 1;
+
+    pub fn purge_before(&mut self, location: isize) {
+        if self.ids.len() < self.purge_len.max(MIN_PURGE_LEN) {
+            return;
+        }
+        self.ids.retain(|&(_, _, _, l), _| l >= location);
+        self.purge_len = self.ids.len() * 2;
+    }
+}
+
+/// the converted or unconverted bases counted at a position
+#[derive(Default)]
+pub struct BaseCalls {
+    pub count: u32,
+    /// sum of phred scores, for the mean quality
+    pub quality_sum: u32,
+    /// the quality string, left empty with `--counts-only`
+    pub qualities: Vec<u8>,
+}
+
+impl BaseCalls {
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub(crate) fn push(&mut self, qual: u8) {
+        self.push_quality(qual, !ARGS.counts_only);
+    }
+
+    /// counts a base of quality `qual`, adding it to the quality string with
+    /// `keep`
+    fn push_quality(&mut self, qual: u8, keep: bool) {
+        self.count += 1;
+        self.quality_sum += u32::from(qual.saturating_sub(33));
+        if keep {
+            self.qualities.push(qual);
+        }
+    }
+
+    /// removes a base of quality `qual` pushed before; nothing if there is
+    /// no such base
+    fn remove(&mut self, qual: u8) {
+        self.remove_quality(qual, !ARGS.counts_only);
+    }
+
+    /// removes a base of quality `qual`, from the quality string if `kept`
+    /// there
+    fn remove_quality(&mut self, qual: u8, kept: bool) {
+        let Some(count) = self.count.checked_sub(1) else {
+            return;
+        };
+        if kept {
+            let Some(i) = self.qualities.iter().position(|&q| q == qual) else {
+                return;
+            };
+            self.qualities.remove(i);
+        }
+        self.count = count;
+        self.quality_sum = self.quality_sum.saturating_sub(u32::from(qual.saturating_sub(33)));
+    }
+
+    pub fn append(&mut self, other: &mut BaseCalls) {
+        self.count += other.count;
+        self.quality_sum += other.quality_sum;
+        self.qualities.append(&mut other.qualities);
+        other.count = 0;
+        other.quality_sum = 0;
+    }
+
+    pub fn mean_quality(&self) -> Option<f64> {
+        (self.count > 0).then(|| f64::from(self.quality_sum) / f64::from(self.count))
+    }
+}
+
 pub struct Position<'a> {
     pub dna: &'a [u8],
     pub location: isize,
     pub strand: Option<u8>,
-    pub converted_qualities: Vec<u8>,
-    pub unconverted_qualities: Vec<u8>,
-    pub unique_ids: BTreeMap<u64, UniqueID>,
+    pub context: Option<Context>,
+    /// index of the `--base-change` counted here
+    pub conversion: u8,
+    /// index of the sample counted here
+    pub sample: u32,
+    pub converted: BaseCalls,
+    pub unconverted: BaseCalls,
 }
 
 impl<'a> Position<'a> {
@@ -39,123 +150,426 @@ impl<'a> Position<'a> {
             dna,
             location,
             strand: None,
-            converted_qualities: Vec::new(),
-            unconverted_qualities: Vec::new(),
-            unique_ids: BTreeMap::new(),
+            context: None,
+            conversion: 0,
+            sample: 0,
+            converted: BaseCalls::default(),
+            unconverted: BaseCalls::default(),
         }
     }
 
-    fn append_read_name_id(&mut self, in_base: &PosQuality, in_align: &Alignment) -> bool {
-        match self.unique_ids.entry(in_align.read_name_id) {
-            std::collections::btree_map::Entry::Vacant(vacant_entry) => {
-                vacant_entry.insert(UniqueID::new(in_align.read_name_id, in_base.converted, in_base.qual));
-                return true;
+    /// the position at 1-based `location` of `text` for the `conversion`th
+    /// `--base-change`, with strand and context set if its base is counted.
+    /// positions of base changes from other bases than C or G have no context.
+    /// soft-masked (lowercase) bases count like uppercase ones unless
+    /// `--exclude-soft-masked`; N, IUPAC ambiguity codes and other bytes like
+    /// '*', '-' or '.' never count.
+    pub fn from_reference(text: &[u8], dna: &'a [u8], location: usize, conversion: u8) -> Self {
+        let ch = text[location - 1];
+        let mut p = Position::new(dna, location as isize);
+        if ARGS.exclude_soft_masked && ch.is_ascii_lowercase() {
+            return p;
+        }
+        let ch = ch.to_ascii_uppercase();
+        p.conversion = conversion;
+        let ((from, from_comp), _) = ARGS.base_change[conversion as usize];
+        let strand = if ch == from {
+            Some(b'+')
+        } else if ch == from_comp {
+            Some(b'-')
+        } else {
+            None
+        };
+        let selected = crate::REGIONS.get().is_none_or(|r| r.contains(dna, location));
+        if let Some(strand) = strand && selected
+          && !crate::SNPS.get().is_some_and(|m| m.is_masked(dna, location, strand)) {
+            let context = Context::of(text, location, strand, conversion);
+            if context.is_none_or(Context::is_counted) {
+                p.strand = Some(strand);
+                p.context = context;
+            }
+        }
+        p
+    }
+
+    fn calls_mut(&mut self, converted: bool) -> &mut BaseCalls {
+        if converted { &mut self.converted } else { &mut self.unconverted }
+    }
+
+    /// whether the base should be counted. a base of a read already counted
+    /// here with the other conversion status is resolved by `--conflict-policy`.
+    fn append_read_name_id(&mut self, in_base: &PosQuality, read_name_id: u64, sample: u32, read_ids: &mut ReadIdTable,
+                           policy: ConflictPolicy) -> bool {
+        match read_ids.ids.entry((read_name_id, sample, self.conversion, self.location)) {
+            std::collections::hash_map::Entry::Vacant(vacant_entry) => {
+                vacant_entry.insert(UniqueID::new(in_base.converted, in_base.qual));
+                true
             },
-            std::collections::btree_map::Entry::Occupied(mut occupied_entry) => {
+            std::collections::hash_map::Entry::Occupied(mut occupied_entry) => {
                 let ent = occupied_entry.get_mut();
                 // if the new base is consistent with exist base's conversion status, ignore
-                // otherwise, delete the exist conversion status
-                if ent.removed {
+                if ent.removed || ent.converted == in_base.converted {
                     return false;
                 }
-                if ent.converted != in_base.converted {
-                    ent.removed = true;
-                    if ent.converted {
-                        for i in 0..self.converted_qualities.len() {
-                            if self.converted_qualities[i] == in_base.qual {
-                                self.converted_qualities.remove(i);
-                                return false;
-                            }
-                        }
-                    } else {
-                        for i in 0..self.unconverted_qualities.len() {
-                            if self.unconverted_qualities[i] == in_base.qual {
-                                self.unconverted_qualities.remove(i);
-                                return false;
-                            }
+                read_ids.conflicts += 1;
+                match policy {
+                    ConflictPolicy::DropBoth => {
+                        ent.removed = true;
+                        self.calls_mut(ent.converted).remove(ent.quality);
+                        false
+                    }
+                    ConflictPolicy::KeepFirst => false,
+                    ConflictPolicy::KeepHigherQuality => {
+                        if in_base.qual <= ent.quality {
+                            return false;
                         }
+                        self.calls_mut(ent.converted).remove(ent.quality);
+                        *ent = UniqueID::new(in_base.converted, in_base.qual);
+                        true
                     }
                 }
-                return false;
             },
         }
+    }
+
+    pub fn append_base(&mut self, input: &PosQuality, a: &Alignment, read_ids: &mut ReadIdTable) {
+        self.count_base(input, a.read_name_id, a.sample, read_ids, ARGS.conflict_policy);
+    }
 
-        // let id = UniqueID::new(a.read_name_id, base.converted, base.qual);
-        // let e = self.unique_ids.get_or_insert(id.clone());
-        // if e.converted != id.converted {
-        //     if e.removed.get() {
-        //         return false;
-        //     }
-        //     if e.converted != id.converted {
-        //         e.removed.set(true);
-        //         if e.converted {
-        //             let i = match self
-        //                 .converted_qualities
-        //                 .as_slice()
-        //                 .iter()
-        //                 .position(|ch| *ch == base.qual)
-        //             {
-        //                 Some(i) => i,
-        //                 None => return false,
-        //             };
-        //             let _ = self.converted_qualities.remove(i);
-        //             return false;
-        //         } else {
-        //             let i = match self
-        //                 .unconverted_qualities
-        //                 .as_slice()
-        //                 .iter()
-        //                 .position(|ch| *ch == base.qual)
-        //             {
-        //                 Some(i) => i,
-        //                 None => return false,
-        //             };
-        //             let _ = self.unconverted_qualities.remove(i);
-        //             return false;
-        //         }
-        //     }
-        //     false
-        // } else {
-        //     true
-        // }
-    }
-
-    pub fn append_base(&mut self, input: &PosQuality, a: &Alignment) {
-        if self.append_read_name_id(input, a) {
+    fn count_base(&mut self, input: &PosQuality, read_name_id: u64, sample: u32, read_ids: &mut ReadIdTable,
+                  policy: ConflictPolicy) {
+        if self.append_read_name_id(input, read_name_id, sample, read_ids, policy) {
             if input.converted {
-                self.converted_qualities.push(input.qual);
+                self.converted.push(input.qual);
             } else {
-                self.unconverted_qualities.push(input.qual);
+                self.unconverted.push(input.qual);
             }
         }
     }
 }
 
 pub fn fill_positions<'a>(positions: &mut Vec<Position<'a>>, text: &'a [u8], dna: &'a [u8],
-                          start_pos: usize, end_pos: usize) {
+                          start_pos: usize, end_pos: usize, conversion: u8) {
     positions.reserve(end_pos - start_pos);
-    let mut last_base = 0u8;
     for i in start_pos..end_pos {
         if i >= text.len() {
             break;
         }
-        let ch = text[i - 1];
-        assert!(ch.is_ascii_alphabetic());
-        let mut p = Position::new(dna, i as isize);
-        if ARGS.cg_only {
-            cold_path();
-            if last_base == b'C' && ch == b'G' {
-                positions.last_mut().unwrap().strand = Some(b'+');
-                p.strand = Some(b'-');
+        positions.push(Position::from_reference(text, dna, i, conversion));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PositionStorage {
+    /// sparse for tasks with less than 1x mean depth, dense otherwise
+    Auto,
+    Dense,
+    Sparse,
+}
+
+/// the positions of a task
+pub enum Positions<'a> {
+    /// one `Position` for every reference base of the task
+    Dense {
+        start: usize,
+        positions: Vec<Position<'a>>,
+    },
+    /// only the positions reached by a counted base
+    Sparse {
+        text: &'a [u8],
+        dna: &'a [u8],
+        range: Range<usize>,
+        conversion: u8,
+        sample: u32,
+        positions: AHashMap<usize, Position<'a>>,
+    },
+}
+
+impl<'a> Positions<'a> {
+    pub fn new(text: &'a [u8], dna: &'a [u8], range: Range<usize>, sparse: bool, conversion: u8, sample: u32) -> Self {
+        if sparse {
+            let range = range.start..range.end.min(text.len());
+            Self::Sparse { text, dna, range, conversion, sample, positions: AHashMap::new() }
+        } else {
+            let mut positions = Vec::new();
+            fill_positions(&mut positions, text, dna, range.start, range.end, conversion);
+            for p in &mut positions {
+                p.sample = sample;
+            }
+            Self::Dense { start: range.start, positions }
+        }
+    }
+
+    pub fn get_mut(&mut self, location: usize) -> Option<&mut Position<'a>> {
+        match self {
+            Self::Dense { start, positions } => positions.get_mut(location.checked_sub(*start)?),
+            Self::Sparse { text, dna, range, conversion, sample, positions } => {
+                if !range.contains(&location) {
+                    return None;
+                }
+                Some(positions.entry(location).or_insert_with(|| {
+                    let mut p = Position::from_reference(text, dna, location, *conversion);
+                    p.sample = *sample;
+                    p
+                }))
+            }
+        }
+    }
+
+    /// the positions, sorted by location
+    pub fn into_vec(self) -> Vec<Position<'a>> {
+        match self {
+            Self::Dense { positions, .. } => positions,
+            Self::Sparse { positions, .. } => {
+                let mut positions: Vec<_> = positions.into_values().collect();
+                positions.sort_unstable_by_key(|p| p.location);
+                positions
+            }
+        }
+    }
+}
+
+/// folds the two halves of each symmetric CpG into one row at the C
+/// coordinate with strand '*', summing counts and concatenating qualities.
+/// `TaskIter2` leaves at least one uncovered base between the position
+/// ranges of tasks, so a CpG split across two tasks has an empty half and the
+/// other half is simply moved to the C coordinate. `positions` are of one `--base-change`, from C
+/// or G. `counted_c` tells whether the C half at a location is counted: a G
+/// whose C is masked (e.g. by `--snp-vcf`) or outside the `--region`s stays
+/// a row of its own.
+pub fn merge_cpg_strands(positions: &mut [Position], conversion: u8, counted_c: impl Fn(isize) -> bool) {
+    let c_strand = if ARGS.base_change[conversion as usize].0.0 == b'C' { b'+' } else { b'-' };
+    let mut i = 0;
+    while i < positions.len() {
+        if positions[i].context != Some(Context::Cg) {
+            i += 1;
+            continue;
+        }
+        if positions[i].strand == Some(c_strand) {
+            positions[i].strand = Some(b'*');
+            let location = positions[i].location;
+            if positions.get(i + 1).is_some_and(|g| g.location == location + 1 && g.context == Some(Context::Cg)) {
+                let (c, g) = positions.split_at_mut(i + 1);
+                let (c, g) = (&mut c[i], &mut g[0]);
+                c.converted.append(&mut g.converted);
+                c.unconverted.append(&mut g.unconverted);
+                i += 1;
             }
         } else {
-            if ch == ARGS.base_change.0.0 {
-                p.strand = Some(b'+');
-            } else if ch == ARGS.base_change.0.1 {
-                p.strand = Some(b'-');
+            // a lone G half: its C is uncovered, lies before the task, or is
+            // not counted. only in the first two cases there is no position
+            // at the C location yet
+            let location = positions[i].location - 1;
+            let c_present = i > 0 && positions[i - 1].location == location;
+            if !c_present && counted_c(location) {
+                positions[i].location = location;
+                positions[i].strand = Some(b'*');
             }
         }
-        positions.push(p);
-        last_base = ch;
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+fn cpg_half(location: isize, strand: u8, converted: usize) -> Position<'static> {
+    let mut p = Position::new(b"chr1", location);
+    p.strand = Some(strand);
+    p.context = Some(Context::Cg);
+    for _ in 0..converted {
+        p.converted.push(b'I');
+    }
+    p.unconverted.push(b'I');
+    p
+}
+
+#[cfg(test)]
+fn merged_rows(positions: &[Position]) -> Vec<(isize, Option<u8>, u32, u32)> {
+    positions
+        .iter()
+        .filter(|p| !p.converted.is_empty() || !p.unconverted.is_empty())
+        .map(|p| (p.location, p.strand, p.converted.count, p.unconverted.count))
+        .collect()
+}
+
+#[test]
+fn test_merge_cpg_strands() {
+    // C,T (the test arguments' first base change) has the C half on '+'. the
+    // first position is the G half of a CpG whose C lies before the task,
+    // the last one a C half whose G lies after it
+    let mut positions = vec![cpg_half(10, b'-', 1), cpg_half(20, b'+', 1), cpg_half(21, b'-', 2), cpg_half(30, b'+', 0)];
+    merge_cpg_strands(&mut positions, 0, |_| true);
+    assert_eq!(merged_rows(&positions), vec![(9, Some(b'*'), 1, 1), (20, Some(b'*'), 3, 2), (30, Some(b'*'), 0, 1)]);
+
+    // G,A has the C half on '-'
+    let mut positions = vec![cpg_half(40, b'-', 1), cpg_half(41, b'+', 0), cpg_half(51, b'+', 2)];
+    merge_cpg_strands(&mut positions, 1, |_| true);
+    assert_eq!(merged_rows(&positions), vec![(40, Some(b'*'), 1, 2), (50, Some(b'*'), 2, 1)]);
+}
+
+#[test]
+fn test_merge_cpg_strands_masked_c() {
+    // a C,T SNP masks the C of the CpG at 100: dense storage keeps an empty,
+    // strandless position there, which the G half must not join
+    let mut masked = Position::new(b"chr1", 100);
+    masked.context = None;
+    let mut positions = vec![masked, cpg_half(101, b'-', 1), cpg_half(200, b'+', 1), cpg_half(201, b'-', 0)];
+    merge_cpg_strands(&mut positions, 0, |location| location != 100);
+    assert_eq!(merged_rows(&positions), vec![(101, Some(b'-'), 1, 1), (200, Some(b'*'), 1, 2)]);
+    let locations: Vec<_> = positions.iter().map(|p| p.location).collect();
+    assert_eq!(locations, vec![100, 101, 200, 201]);
+
+    // sparse storage has no position for a masked C either
+    let mut positions = vec![cpg_half(101, b'-', 1)];
+    merge_cpg_strands(&mut positions, 0, |location| location != 100);
+    assert_eq!(merged_rows(&positions), vec![(101, Some(b'-'), 1, 1)]);
+}
+
+#[test]
+fn test_merge_cpg_strands_region_start() {
+    // --region chr1:1002-3000 starts on the G of the CpG at 1001, whose C is
+    // not counted: the G stays inside the region
+    let in_region = |location: isize| (1002..=3000).contains(&location);
+    let mut positions = vec![cpg_half(1002, b'-', 2), cpg_half(1010, b'+', 1)];
+    merge_cpg_strands(&mut positions, 0, in_region);
+    assert_eq!(merged_rows(&positions), vec![(1002, Some(b'-'), 2, 1), (1010, Some(b'*'), 1, 1)]);
+}
+
+#[cfg(test)]
+fn base(location: isize, converted: bool, qual: u8) -> PosQuality {
+    PosQuality {
+        converted,
+        qual,
+        ..PosQuality::new(location)
+    }
+}
+
+#[test]
+fn test_read_ids_per_conversion() {
+    // the mates of a read disagree at a position counted for both base
+    // changes of the test arguments
+    let mut read_ids = ReadIdTable::default();
+    let mut c_to_t = Position::new(b"chr1", 5);
+    let mut g_to_a = Position::new(b"chr1", 5);
+    g_to_a.conversion = 1;
+    let policy = ConflictPolicy::DropBoth;
+    c_to_t.count_base(&base(5, true, b'I'), 7, 0, &mut read_ids, policy);
+    g_to_a.count_base(&base(5, false, b'I'), 7, 0, &mut read_ids, policy);
+    g_to_a.count_base(&base(5, true, b'I'), 7, 0, &mut read_ids, policy);
+    assert_eq!((c_to_t.converted.count, c_to_t.unconverted.count), (1, 0));
+    assert_eq!((g_to_a.converted.count, g_to_a.unconverted.count), (0, 0));
+    assert_eq!(read_ids.conflicts, 1);
+}
+
+#[cfg(test)]
+/// (converted, unconverted, quality sums) after an unconverted base of
+/// quality `first` and a converted one of quality `second` of the same read,
+/// and a converted base of another read
+fn resolve_conflict(policy: ConflictPolicy, first: u8, second: u8) -> ((u32, u32), (u32, u32), usize) {
+    let mut read_ids = ReadIdTable::default();
+    let mut p = Position::new(b"chr1", 5);
+    p.count_base(&base(5, false, first), 1, 0, &mut read_ids, policy);
+    p.count_base(&base(5, true, second), 1, 0, &mut read_ids, policy);
+    p.count_base(&base(5, true, b'+'), 2, 0, &mut read_ids, policy);
+    ((p.converted.count, p.unconverted.count), (p.converted.quality_sum, p.unconverted.quality_sum), read_ids.conflicts)
+}
+
+#[test]
+fn test_conflict_policy() {
+    assert_eq!(resolve_conflict(ConflictPolicy::DropBoth, b'5', b'?'), ((1, 0), (10, 0), 1));
+    assert_eq!(resolve_conflict(ConflictPolicy::KeepFirst, b'5', b'?'), ((1, 1), (10, 20), 1));
+    assert_eq!(resolve_conflict(ConflictPolicy::KeepHigherQuality, b'5', b'?'), ((2, 0), (40, 0), 1));
+    assert_eq!(resolve_conflict(ConflictPolicy::KeepHigherQuality, b'5', b'5'), ((1, 1), (10, 20), 1));
+
+    // removing a base never pushed leaves the counts alone
+    let mut calls = BaseCalls::default();
+    calls.remove(b'I');
+    calls.push(b'5');
+    calls.remove(b'I');
+    assert_eq!((calls.count, calls.quality_sum), (1, 20));
+}
+
+#[test]
+fn test_purge_read_ids() {
+    let policy = ConflictPolicy::DropBoth;
+    let mut read_ids = ReadIdTable::default();
+    let mut at_5 = Position::new(b"chr1", 5);
+    let mut at_10 = Position::new(b"chr1", 10);
+    at_5.count_base(&base(5, true, b'I'), 1, 0, &mut read_ids, policy);
+    at_10.count_base(&base(10, true, b'I'), 1, 0, &mut read_ids, policy);
+    // a small table is left alone
+    read_ids.purge_before(10);
+    assert_eq!(read_ids.ids.len(), 2);
+
+    for id in 0..MIN_PURGE_LEN as u64 {
+        read_ids.ids.insert((100 + id, 0, 0, 20), UniqueID::new(true, b'I'));
+    }
+    read_ids.purge_before(10);
+    assert!(!read_ids.ids.contains_key(&(1, 0, 0, 5)));
+    assert!(read_ids.ids.contains_key(&(1, 0, 0, 10)));
+    assert_eq!(read_ids.ids.len(), MIN_PURGE_LEN + 1);
+    // the next purge waits for the table to double
+    read_ids.purge_before(30);
+    assert_eq!(read_ids.ids.len(), MIN_PURGE_LEN + 1);
+
+    // the read is still known at the kept location
+    at_10.count_base(&base(10, true, b'I'), 1, 0, &mut read_ids, policy);
+    assert_eq!(at_10.converted.count, 1);
+    at_10.count_base(&base(10, false, b'I'), 1, 0, &mut read_ids, policy);
+    assert_eq!((at_10.converted.count, at_10.unconverted.count, read_ids.conflicts), (0, 0, 1));
+}
+
+#[test]
+fn test_counts_only() {
+    // '5', '?' and 'I' are phred 20, 30 and 40
+    for keep in [false, true] {
+        let mut calls = BaseCalls::default();
+        for qual in *b"5?I" {
+            calls.push_quality(qual, keep);
+        }
+        assert_eq!((calls.count, calls.mean_quality()), (3, Some(30.0)));
+        calls.remove_quality(b'5', keep);
+        assert_eq!((calls.count, calls.mean_quality()), (2, Some(35.0)));
+        calls.remove_quality(b'I', keep);
+        assert_eq!((calls.count, calls.mean_quality()), (1, Some(30.0)));
+        assert_eq!(calls.qualities, if keep { &b"?"[..] } else { b"" });
+        calls.remove_quality(b'?', keep);
+        assert_eq!((calls.count, calls.mean_quality()), (0, None));
     }
 }
+
+#[test]
+fn test_from_reference() {
+    let text = b"AC*G-cN.g";
+    let strands: Vec<_> = (1..=text.len()).map(|i| Position::from_reference(text, b"chr1", i, 0).strand).collect();
+    assert_eq!(strands, [None, Some(b'+'), None, Some(b'-'), None, Some(b'+'), None, None, Some(b'-')]);
+    let g_to_a = Position::from_reference(text, b"chr1", 4, 1);
+    assert_eq!((g_to_a.strand, g_to_a.conversion), (Some(b'+'), 1));
+}
+
+#[test]
+fn test_dense_and_sparse_positions() {
+    // (location, read, converted, quality): a C,T read pair overlapping at 6,
+    // bases on '-' Gs, on non-C/G bases and outside the range
+    let bases = [(3, 1, true, b'I'), (6, 1, false, b'5'), (6, 2, true, b'?'), (6, 1, false, b'I'), (2, 3, true, b'I'),
+        (11, 2, false, b'5'), (4, 2, true, b'I'), (1, 4, true, b'I'), (13, 4, true, b'I'), (11, 3, true, b'?')];
+    let text = b"ACGTCCGATCGA";
+    let rows = |sparse| {
+        let mut read_ids = ReadIdTable::default();
+        let mut positions = Positions::new(text, b"chr1", 2..13, sparse, 0, 0);
+        for (location, read, converted, qual) in bases {
+            if let Some(p) = positions.get_mut(location) && p.strand.is_some() {
+                p.count_base(&base(location as isize, converted, qual), read, 0, &mut read_ids, ConflictPolicy::DropBoth);
+            }
+        }
+        positions
+            .into_vec()
+            .into_iter()
+            .filter(|p| !p.converted.is_empty() || !p.unconverted.is_empty())
+            .map(|p| (p.location, p.strand, p.context, p.converted.qualities, p.converted.count, p.unconverted.qualities, p.unconverted.count))
+            .collect::<Vec<_>>()
+    };
+    let dense = rows(false);
+    assert_eq!(dense.iter().map(|r| r.0).collect::<Vec<_>>(), vec![2, 3, 6, 11]);
+    assert_eq!(dense[2], (6, Some(b'+'), Some(Context::Cg), b"?".to_vec(), 1, b"5".to_vec(), 1));
+    assert_eq!(dense, rows(true));
+}
@@ -0,0 +1,230 @@
+/// the background rate of the tested count, for the `pValue` column
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorRate {
+    Fixed(f64),
+    /// estimated from the spike-in control contigs
+    Control,
+}
+
+/// parses a rate in (0, 1) or "control"
+pub fn parse_error_rate(s: &str) -> Result<ErrorRate, String> {
+    if s == "control" {
+        return Ok(ErrorRate::Control);
+    }
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate < 1.0 => Ok(ErrorRate::Fixed(rate)),
+        _ => Err(format!("invalid error rate (a number in (0, 1) or 'control'): {}", s)),
+    }
+}
+
+/// which count of a position is tested against `--error-rate`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TestCount {
+    Converted,
+    Unconverted,
+}
+
+/// ln Γ(x) for x > 0, by the Lanczos approximation (g = 7, n = 9)
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // reflection: Γ(x) Γ(1 - x) = π / sin(πx)
+        return (std::f64::consts::PI / (std::f64::consts::PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| sum + c / (x + i as f64 + 1.0));
+    let t = x + G + 0.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+fn ln_beta(a: f64, b: f64) -> f64 {
+    ln_gamma(a) + ln_gamma(b) - ln_gamma(a + b)
+}
+
+/// the continued fraction of the incomplete beta function, by the modified
+/// Lentz method
+fn beta_continued_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..10000 {
+        let m = f64::from(m);
+        for numerator in [
+            m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m)),
+            -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0)),
+        ] {
+            d = 1.0 + numerator * d;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = 1.0 + numerator / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1.0 / d;
+            h *= d * c;
+        }
+        if (d * c - 1.0).abs() < 1e-15 {
+            break;
+        }
+    }
+    h
+}
+
+/// the regularized incomplete beta function I_x(a, b)
+fn regularized_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front = (a * x.ln() + b * (1.0 - x).ln() - ln_beta(a, b)).exp();
+    // the continued fraction converges fast below the mean of the beta
+    // distribution, and I_x(a, b) = 1 - I_{1-x}(b, a) above it
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_continued_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_continued_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+/// P(X >= k) for X ~ Beta-binomial(n, alpha, beta). the terms are summed
+/// from k outwards over the tail not holding the mean, until they no longer
+/// add to the sum.
+fn beta_binomial_upper_tail(k: u32, n: u32, alpha: f64, beta: f64) -> f64 {
+    let n_f = f64::from(n);
+    let ln_pmf = |i: u32| {
+        let i = f64::from(i);
+        let ln_choose = ln_gamma(n_f + 1.0) - ln_gamma(i + 1.0) - ln_gamma(n_f - i + 1.0);
+        ln_choose + ln_beta(i + alpha, n_f - i + beta) - ln_beta(alpha, beta)
+    };
+    // pmf(i + 1) / pmf(i)
+    let ratio = |i: u32| {
+        let i = f64::from(i);
+        (n_f - i) / (i + 1.0) * (i + alpha) / (n_f - i - 1.0 + beta)
+    };
+    let mean = n_f * alpha / (alpha + beta);
+    let mut sum = 0.0;
+    if f64::from(k) > mean {
+        let mut term = ln_pmf(k).exp();
+        for i in k..=n {
+            sum += term;
+            if i == n || term <= sum * f64::EPSILON {
+                break;
+            }
+            term *= ratio(i);
+        }
+        sum.min(1.0)
+    } else {
+        // one minus the lower tail below k
+        let mut term = ln_pmf(k - 1).exp();
+        for i in (0..k).rev() {
+            sum += term;
+            if i == 0 || term <= sum * f64::EPSILON {
+                break;
+            }
+            term /= ratio(i - 1);
+        }
+        (1.0 - sum).max(0.0)
+    }
+}
+
+/// P(X >= k) for X ~ Binomial(n, p), or for `rho` > 0 the beta-binomial of
+/// mean p and intra-class correlation `rho`
+pub fn upper_tail(k: u32, n: u32, p: f64, rho: f64) -> f64 {
+    if k == 0 {
+        return 1.0;
+    }
+    if k > n {
+        return 0.0;
+    }
+    if rho > 0.0 {
+        let alpha = p * (1.0 - rho) / rho;
+        let beta = (1.0 - p) * (1.0 - rho) / rho;
+        return beta_binomial_upper_tail(k, n, alpha, beta);
+    }
+    regularized_beta(f64::from(k), f64::from(n - k + 1), p)
+}
+
+#[test]
+fn test_upper_tail() {
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+    assert!(close(upper_tail(0, 10, 0.1, 0.0), 1.0));
+    assert!(close(upper_tail(10, 10, 0.5, 0.0), 0.5f64.powi(10)));
+    // 1 - P(X = 0)
+    assert!(close(upper_tail(1, 3, 0.2, 0.0), 1.0 - 0.8f64.powi(3)));
+    // overdispersion widens the tail
+    assert!(upper_tail(5, 20, 0.05, 0.1) > upper_tail(5, 20, 0.05, 0.0));
+}
+
+#[cfg(test)]
+/// P(X >= k) summed term by term
+fn summed_upper_tail(k: u32, n: u32, p: f64, rho: f64) -> f64 {
+    let n_f = f64::from(n);
+    (k..=n)
+        .map(|i| {
+            let i = f64::from(i);
+            let ln_choose = ln_gamma(n_f + 1.0) - ln_gamma(i + 1.0) - ln_gamma(n_f - i + 1.0);
+            let ln_pmf = if rho > 0.0 {
+                let alpha = p * (1.0 - rho) / rho;
+                let beta = (1.0 - p) * (1.0 - rho) / rho;
+                ln_choose + ln_beta(i + alpha, n_f - i + beta) - ln_beta(alpha, beta)
+            } else {
+                ln_choose + i * p.ln() + (n_f - i) * (1.0 - p).ln()
+            };
+            ln_pmf.exp()
+        })
+        .sum()
+}
+
+#[test]
+fn test_ln_gamma() {
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-10 * b.abs().max(1.0);
+    assert!(close(ln_gamma(1.0), 0.0));
+    assert!(close(ln_gamma(2.0), 0.0));
+    assert!(close(ln_gamma(0.5), std::f64::consts::PI.sqrt().ln()));
+    assert!(close(ln_gamma(10.0), 362880f64.ln()));
+    assert!(close(ln_gamma(0.1), 2.252712651734206));
+    // Stirling's series
+    let x = 1e6f64;
+    assert!(close(ln_gamma(x), (x - 0.5) * x.ln() - x + 0.5 * (2.0 * std::f64::consts::PI).ln() + 1.0 / (12.0 * x)));
+}
+
+#[test]
+fn test_tails_match_sums() {
+    let close = |a: f64, b: f64| (a - b).abs() <= 1e-9 * b.max(1e-300);
+    for (n, p) in [(10, 0.1), (50, 0.02), (200, 0.5), (1000, 0.001)] {
+        for k in [1, 2, 5, n / 3, n / 2, n - 1, n] {
+            for rho in [0.0, 0.01, 0.3] {
+                let (tail, sum) = (upper_tail(k, n, p, rho), summed_upper_tail(k, n, p, rho));
+                assert!(close(tail, sum), "k {} n {} p {} rho {}: {} != {}", k, n, p, rho, tail, sum);
+            }
+        }
+    }
+    assert_eq!(upper_tail(11, 10, 0.5, 0.0), 0.0);
+    // deep depths take no time
+    assert!(upper_tail(5000, 10_000_000, 0.001, 0.0) > 0.99);
+    let overdispersed = upper_tail(20000, 10_000_000, 0.001, 0.01);
+    assert!(overdispersed > 0.0 && overdispersed < 1.0);
+    assert!(upper_tail(100, 1000, 0.001, 0.0) < 1e-100);
+}
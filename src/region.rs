@@ -0,0 +1,167 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::ops::Range;
+use std::path::Path;
+
+use ahash::AHashMap;
+use anyhow::{anyhow, Context as _, Result};
+
+/// a 1-based half-open interval on a dna
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub dna: Vec<u8>,
+    pub range: Range<usize>,
+}
+
+/// parses chr, chr:start or chr:start-end (1-based, inclusive, commas allowed)
+pub fn parse_region(s: &str) -> Result<Region, String> {
+    let whole = |dna: &str| Region { dna: dna.as_bytes().to_vec(), range: 1..usize::MAX };
+    let Some((dna, span)) = s.rsplit_once(':') else {
+        return Ok(whole(s));
+    };
+    let span = span.replace(',', "");
+    let parse = |n: &str| n.parse::<usize>().ok().filter(|&n| n > 0);
+    let (start, end) = match span.split_once('-') {
+        Some((start, end)) => (parse(start), parse(end)),
+        None => (parse(&span), Some(usize::MAX - 1)),
+    };
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => Ok(Region { dna: dna.as_bytes().to_vec(), range: start..end + 1 }),
+        // a dna name containing ':'
+        _ if span.bytes().any(|b| !b.is_ascii_digit() && b != b'-') => Ok(whole(s)),
+        _ => Err(format!("invalid region: {}", s)),
+    }
+}
+
+/// reads the intervals of a BED file (0-based, half-open)
+pub fn read_bed(path: &Path) -> Result<Vec<Region>> {
+    let reader = BufReader::new(File::open(path).with_context(|| format!("cannot open {}", path.display()))?);
+    let mut regions = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let bad_line = || anyhow!("{}:{}: invalid BED line: {}", path.display(), i + 1, line);
+        let dna = fields.next().ok_or_else(bad_line)?;
+        let start: usize = fields.next().and_then(|s| s.trim().parse().ok()).ok_or_else(bad_line)?;
+        let end: usize = fields.next().and_then(|s| s.trim().parse().ok()).ok_or_else(bad_line)?;
+        regions.push(Region { dna: dna.as_bytes().to_vec(), range: start + 1..end + 1 });
+    }
+    Ok(regions)
+}
+
+/// sorted, merged intervals per dna
+#[derive(Default)]
+pub struct Intervals {
+    by_dna: AHashMap<Vec<u8>, Vec<Range<usize>>>,
+}
+
+impl Intervals {
+    pub fn new(regions: impl IntoIterator<Item = Region>) -> Self {
+        let mut by_dna: AHashMap<Vec<u8>, Vec<Range<usize>>> = AHashMap::new();
+        for region in regions {
+            if !region.range.is_empty() {
+                by_dna.entry(region.dna).or_default().push(region.range);
+            }
+        }
+        for ranges in by_dna.values_mut() {
+            ranges.sort_by_key(|r| r.start);
+            let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+            for r in ranges.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                    _ => merged.push(r),
+                }
+            }
+            *ranges = merged;
+        }
+        Self { by_dna }
+    }
+
+    pub fn contains_dna(&self, dna: &[u8]) -> bool {
+        self.by_dna.contains_key(dna)
+    }
+
+    /// the interval containing `location`, if any
+    pub fn find(&self, dna: &[u8], location: usize) -> Option<&Range<usize>> {
+        let ranges = self.by_dna.get(dna)?;
+        let i = ranges.partition_point(|r| r.end <= location);
+        ranges.get(i).filter(|r| r.start <= location)
+    }
+
+    pub fn overlaps(&self, dna: &[u8], range: &Range<usize>) -> bool {
+        let Some(ranges) = self.by_dna.get(dna) else {
+            return false;
+        };
+        let i = ranges.partition_point(|r| r.end <= range.start);
+        ranges.get(i).is_some_and(|r| r.start < range.end)
+    }
+
+    pub fn covers(&self, dna: &[u8], range: &Range<usize>) -> bool {
+        self.find(dna, range.start).is_some_and(|r| r.end >= range.end)
+    }
+}
+
+/// the reference positions selected by `--region`, `--targets` and `--exclude`
+pub struct RegionFilter {
+    /// everything if none
+    include: Option<Intervals>,
+    exclude: Intervals,
+}
+
+impl RegionFilter {
+    pub fn new(regions: &[Region], targets: &[impl AsRef<Path>], exclude: &[impl AsRef<Path>]) -> Result<Self> {
+        let include = if regions.is_empty() && targets.is_empty() {
+            None
+        } else {
+            let mut all = regions.to_vec();
+            for path in targets {
+                all.extend(read_bed(path.as_ref())?);
+            }
+            Some(Intervals::new(all))
+        };
+        let mut excluded = Vec::new();
+        for path in exclude {
+            excluded.extend(read_bed(path.as_ref())?);
+        }
+        Ok(Self { include, exclude: Intervals::new(excluded) })
+    }
+
+    pub fn contains_dna(&self, dna: &[u8]) -> bool {
+        self.include.as_ref().is_none_or(|i| i.contains_dna(dna))
+    }
+
+    pub fn contains(&self, dna: &[u8], location: usize) -> bool {
+        self.include.as_ref().is_none_or(|i| i.find(dna, location).is_some()) && self.exclude.find(dna, location).is_none()
+    }
+
+    /// whether any position of `range` may be selected
+    pub fn overlaps(&self, dna: &[u8], range: &Range<usize>) -> bool {
+        self.include.as_ref().is_none_or(|i| i.overlaps(dna, range)) && !self.exclude.covers(dna, range)
+    }
+}
+
+#[test]
+fn test_parse_region() {
+    let r = parse_region("chr1:1,000-2000").unwrap();
+    assert_eq!((r.dna.as_slice(), r.range), (&b"chr1"[..], 1000..2001));
+    assert_eq!(parse_region("chrM").unwrap().range, 1..usize::MAX);
+    assert_eq!(parse_region("HLA-A*01:01:01:01N").unwrap().dna, b"HLA-A*01:01:01:01N");
+    assert!(parse_region("chr1:20-10").is_err());
+}
+
+#[test]
+fn test_intervals() {
+    let region = |s| parse_region(s).unwrap();
+    let intervals = Intervals::new([region("chr1:10-20"), region("chr1:15-30"), region("chr1:50-60")]);
+    assert!(intervals.find(b"chr1", 9).is_none());
+    assert_eq!(intervals.find(b"chr1", 25), Some(&(10..31)));
+    assert!(intervals.find(b"chr1", 31).is_none());
+    assert!(intervals.overlaps(b"chr1", &(31..51)));
+    assert!(!intervals.overlaps(b"chr1", &(31..50)));
+    assert!(intervals.covers(b"chr1", &(12..31)));
+    assert!(!intervals.covers(b"chr1", &(12..32)));
+    assert!(!intervals.overlaps(b"chr2", &(1..100)));
+}
@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use ahash::AHashMap;
+use anyhow::{bail, Context as _, Result};
+use flate2::read::MultiGzDecoder;
+
+use crate::BaseChange;
+
+const PLUS: u8 = 1;
+const MINUS: u8 = 2;
+
+/// known variants from `--snp-vcf`; their positions are not counted since
+/// e.g. a C>T SNP looks just like a conversion.
+pub struct SnpMask {
+    /// sorted (location, strand bits) per dna
+    by_dna: AHashMap<Vec<u8>, Vec<(usize, u8)>>,
+}
+
+fn open_vcf(path: &Path) -> Result<Box<dyn BufRead>> {
+    let mut file = BufReader::new(File::open(path).with_context(|| format!("cannot open {}", path.display()))?);
+    if file.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// a typed value of a BCF record holding a string, and the bytes after it
+fn typed_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&typ, mut data) = data.split_first()?;
+    let mut len = usize::from(typ >> 4);
+    if len == 15 {
+        // the length follows as a typed integer
+        let (&int_typ, rest) = data.split_first()?;
+        let width = match int_typ & 0xf {
+            1 => 1,
+            2 => 2,
+            3 => 4,
+            _ => return None,
+        };
+        let mut bytes = [0; 4];
+        bytes[..width].copy_from_slice(rest.get(..width)?);
+        len = u32::from_le_bytes(bytes) as usize;
+        data = &rest[width..];
+    }
+    if !matches!(typ & 0xf, 0 | 7) || data.len() < len {
+        return None;
+    }
+    let (value, rest) = data.split_at(len);
+    Some((value.split(|&b| b == 0).next().unwrap_or_default(), rest))
+}
+
+/// the contig names of a BCF header by index: in `##contig` order, unless
+/// given by their `IDX`
+fn bcf_contigs(header: &[u8]) -> Vec<Vec<u8>> {
+    let mut contigs = Vec::new();
+    for line in header.split(|&b| b == b'\n') {
+        let Some(fields) = line.strip_prefix(b"##contig=<").and_then(|l| l.strip_suffix(b">")) else {
+            continue;
+        };
+        let field = |key: &[u8]| fields.split(|&b| b == b',').find_map(|f| f.strip_prefix(key));
+        let Some(id) = field(b"ID=") else {
+            continue;
+        };
+        let idx = field(b"IDX=").and_then(|i| atoi_simd::parse(i).ok()).unwrap_or(contigs.len());
+        if contigs.len() <= idx {
+            contigs.resize(idx + 1, Vec::new());
+        }
+        contigs[idx] = id.to_vec();
+    }
+    contigs
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// calls `add` with (dna, 1-based position, reference, alternate alleles)
+/// of each record of the uncompressed BCF `reader`
+fn read_bcf(mut reader: impl BufRead, name: &str, mut add: impl FnMut(&[u8], usize, &[u8], &[&[u8]])) -> Result<()> {
+    let mut magic = [0; 5];
+    reader.read_exact(&mut magic)?;
+    if magic[3] != 2 {
+        bail!("{}: BCF version {}.{} is not supported", name, magic[3], magic[4]);
+    }
+    let mut header = vec![0; read_u32(&mut reader)? as usize];
+    reader.read_exact(&mut header)?;
+    let contigs = bcf_contigs(&header);
+    let mut shared = Vec::new();
+    for i in 1.. {
+        if reader.fill_buf()?.is_empty() {
+            break;
+        }
+        let invalid = || format!("{}: invalid BCF record {}", name, i);
+        shared.resize(read_u32(&mut reader)? as usize, 0);
+        let indiv = read_u32(&mut reader)?;
+        reader.read_exact(&mut shared).with_context(invalid)?;
+        std::io::copy(&mut reader.by_ref().take(u64::from(indiv)), &mut std::io::sink())?;
+        let int = |at: usize| shared.get(at..at + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+        let (Some(chrom), Some(pos), Some(n_allele_info)) = (int(0), int(4), int(16)) else {
+            bail!(invalid());
+        };
+        let dna = contigs.get(chrom as usize).filter(|c| !c.is_empty()).with_context(invalid)?;
+        // the ID, then the alleles
+        let (_, mut rest) = typed_string(&shared[24..]).with_context(invalid)?;
+        let mut alleles = Vec::new();
+        for _ in 0..n_allele_info >> 16 {
+            let (allele, after) = typed_string(rest).with_context(invalid)?;
+            alleles.push(allele);
+            rest = after;
+        }
+        let Some((reference, alts)) = alleles.split_first() else {
+            continue;
+        };
+        add(dna, pos as usize + 1, reference, alts);
+    }
+    Ok(())
+}
+
+/// calls `add` with (dna, 1-based position, reference, alternate alleles)
+/// of each record of the VCF `reader`
+fn read_vcf(reader: impl BufRead, name: &str, mut add: impl FnMut(&[u8], usize, &[u8], &[&[u8]])) -> Result<()> {
+    for (i, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let fields: Vec<&[u8]> = line.split(|&b| b == b'\t').take(5).collect();
+        let [dna, pos, _, reference, alts] = fields[..] else {
+            bail!("{}:{}: invalid VCF record", name, i + 1);
+        };
+        let pos: usize = atoi_simd::parse(pos).ok().with_context(|| format!("{}:{}: invalid position", name, i + 1))?;
+        let alts: Vec<&[u8]> = alts.split(|&b| b == b',').collect();
+        add(dna, pos, reference, &alts);
+    }
+    Ok(())
+}
+
+impl SnpMask {
+    /// reads a VCF or BCF (plain or bgzipped). with `transitions_only`, only
+    /// single-base variants from a converted base to its conversion product
+    /// (e.g. C>T, or G>A for the '-' strand) are masked, and only on that
+    /// strand; otherwise every reference base of every variant is masked.
+    /// masked positions are not counted for any read, whatever its allele.
+    pub fn from_vcf(path: &Path, transitions_only: bool, base_changes: &[BaseChange]) -> Result<Self> {
+        Self::from_reader(open_vcf(path)?, &path.display().to_string(), transitions_only, base_changes)
+    }
+
+    fn from_reader(mut reader: impl BufRead, name: &str, transitions_only: bool, base_changes: &[BaseChange]) -> Result<Self> {
+        let mut by_dna: AHashMap<Vec<u8>, Vec<(usize, u8)>> = AHashMap::new();
+        let add = |dna: &[u8], pos: usize, reference: &[u8], alts: &[&[u8]]| {
+            let reference = reference.to_ascii_uppercase();
+            if !by_dna.contains_key(dna) {
+                by_dna.insert(dna.to_vec(), Vec::new());
+            }
+            let sites = by_dna.get_mut(dna).unwrap();
+            for alt in alts {
+                if matches!(*alt, b"." | b"*") || alt.starts_with(b"<") {
+                    continue;
+                }
+                if !transitions_only {
+                    sites.extend((0..reference.len()).map(|j| (pos + j, PLUS | MINUS)));
+                    continue;
+                }
+                let (&[r], &[a]) = (reference.as_slice(), alt.to_ascii_uppercase().as_slice()) else {
+                    continue;
+                };
+                for &((from, from_comp), (to, to_comp)) in base_changes {
+                    if (r, a) == (from, to) {
+                        sites.push((pos, PLUS));
+                    }
+                    if (r, a) == (from_comp, to_comp) {
+                        sites.push((pos, MINUS));
+                    }
+                }
+            }
+        };
+        if reader.fill_buf()?.starts_with(b"BCF") {
+            read_bcf(reader, name, add)?;
+        } else {
+            read_vcf(reader, name, add)?;
+        }
+        for sites in by_dna.values_mut() {
+            sites.sort_unstable();
+            sites.dedup_by(|b, a| {
+                let same = a.0 == b.0;
+                if same {
+                    a.1 |= b.1;
+                }
+                same
+            });
+        }
+        Ok(Self { by_dna })
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_dna.values().map(Vec::len).sum()
+    }
+
+    pub fn is_masked(&self, dna: &[u8], location: usize, strand: u8) -> bool {
+        let Some(sites) = self.by_dna.get(dna) else {
+            return false;
+        };
+        let bit = if strand == b'-' { MINUS } else { PLUS };
+        sites
+            .binary_search_by_key(&location, |&(l, _)| l)
+            .is_ok_and(|i| sites[i].1 & bit != 0)
+    }
+}
+
+#[cfg(test)]
+const TEST_VCF: &[u8] = b"##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\nchr1\t10\t.\tC\tT\nchr1\t20\trs1\tG\tA,C\nchr1\t30\t.\tAC\tA\nchr2\t5\t.\tC\t<DEL>\n";
+
+#[test]
+fn test_vcf_mask() {
+    let mask = SnpMask::from_reader(TEST_VCF, "test.vcf", false, &crate::ARGS.base_change).unwrap();
+    assert_eq!(mask.len(), 4);
+    for (location, masked) in [(10, true), (11, false), (20, true), (30, true), (31, true), (32, false)] {
+        assert_eq!(mask.is_masked(b"chr1", location, b'+'), masked, "{}", location);
+        assert_eq!(mask.is_masked(b"chr1", location, b'-'), masked, "{}", location);
+    }
+    assert!(!mask.is_masked(b"chr2", 5, b'+'));
+    assert!(!mask.is_masked(b"chr3", 10, b'+'));
+
+    // for C,T a C>T SNP looks like a conversion on '+', G>A on '-'
+    let mask = SnpMask::from_reader(TEST_VCF, "test.vcf", true, &crate::ARGS.base_change[..1]).unwrap();
+    assert_eq!(mask.len(), 2);
+    assert!(mask.is_masked(b"chr1", 10, b'+') && !mask.is_masked(b"chr1", 10, b'-'));
+    assert!(mask.is_masked(b"chr1", 20, b'-') && !mask.is_masked(b"chr1", 20, b'+'));
+    assert!(!mask.is_masked(b"chr1", 30, b'+'));
+    // and for G,A as well on the other strand
+    let mask = SnpMask::from_reader(TEST_VCF, "test.vcf", true, &crate::ARGS.base_change).unwrap();
+    assert!(mask.is_masked(b"chr1", 10, b'+') && mask.is_masked(b"chr1", 10, b'-'));
+
+    assert!(SnpMask::from_reader(&b"chr1\tx\t.\tC\tT\n"[..], "test.vcf", false, &crate::ARGS.base_change).is_err());
+}
+
+#[cfg(test)]
+fn bcf_string(value: &[u8]) -> Vec<u8> {
+    if value.len() < 15 {
+        [&[(value.len() as u8) << 4 | 7][..], value].concat()
+    } else {
+        [&[0xf7, 0x11, value.len() as u8][..], value].concat()
+    }
+}
+
+#[cfg(test)]
+fn bcf_record(chrom: u32, pos: u32, alleles: &[&[u8]]) -> Vec<u8> {
+    let mut shared = [chrom, pos, 1, 0, (alleles.len() as u32) << 16, 0].map(u32::to_le_bytes).concat();
+    shared.extend(bcf_string(b"."));
+    for allele in alleles {
+        shared.extend(bcf_string(allele));
+    }
+    let indiv = b"xyz";
+    [&(shared.len() as u32).to_le_bytes()[..], &(indiv.len() as u32).to_le_bytes(), &shared, indiv].concat()
+}
+
+#[test]
+fn test_bcf_mask() {
+    let header = b"##fileformat=VCFv4.2\n##contig=<ID=chr2,IDX=1>\n##contig=<ID=chr1,IDX=0>\n#CHROM\tPOS\tID\tREF\tALT\n\0";
+    let bcf = [
+        &b"BCF\x02\x02"[..],
+        &(header.len() as u32).to_le_bytes(),
+        header,
+        &bcf_record(0, 9, &[b"C", b"T"]),
+        &bcf_record(1, 19, &[b"G", b"A", b"C"]),
+        &bcf_record(0, 29, &[b"CCCCCCCCCCCCCCCCC", b"C"]),
+    ].concat();
+    let mask = SnpMask::from_reader(bcf.as_slice(), "test.bcf", false, &crate::ARGS.base_change).unwrap();
+    assert_eq!(mask.len(), 19);
+    assert!(mask.is_masked(b"chr1", 10, b'+') && mask.is_masked(b"chr2", 20, b'-'));
+    assert!(mask.is_masked(b"chr1", 46, b'+') && !mask.is_masked(b"chr1", 47, b'+'));
+    assert!(!mask.is_masked(b"chr1", 20, b'+'));
+
+    let truncated = &bcf[..bcf.len() - 4];
+    assert!(SnpMask::from_reader(truncated, "test.bcf", false, &crate::ARGS.base_change).is_err());
+}
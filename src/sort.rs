@@ -0,0 +1,185 @@
+// external coordinate sort for --sort-input
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ahash::AHashMap;
+use anyhow::Result;
+
+use crate::task::record_key;
+
+type SortKey = (usize, usize);
+
+/// a unique prefix for the temporary files of one sort or merge
+fn temp_prefix() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!("hisat-3n-table.{}.{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// a temporary file, removed when dropped
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create(tmp_dir: &Path, prefix: &str, suffix: &str) -> Result<(Self, BufWriter<File>)> {
+        let path = tmp_dir.join(format!("{}.{}", prefix, suffix));
+        let file = File::create(&path)?;
+        Ok((Self { path }, BufWriter::new(file)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// `path`, or standard input for '-'
+fn open_input(path: &Path) -> Result<Box<dyn BufRead>> {
+    if path.as_os_str() == "-" {
+        Ok(Box::new(std::io::stdin().lock()))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+/// copies standard input to a file in `tmp_dir`, which can be mapped
+pub fn spool_stdin(tmp_dir: &Path) -> Result<TempFile> {
+    let (spooled, mut output) = TempFile::create(tmp_dir, &temp_prefix(), "stdin.sam")?;
+    std::io::copy(&mut std::io::stdin().lock(), &mut output)?;
+    output.flush()?;
+    Ok(spooled)
+}
+
+/// dna rank, then location. dnas are ranked in `@SQ` header order, then by
+/// first appearance; unplaced ('*') and malformed records go last.
+fn sort_key(ranks: &AHashMap<Vec<u8>, usize>, line: &[u8]) -> SortKey {
+    match record_key(line) {
+        Some((dna, pos)) => (ranks.get(dna).copied().unwrap_or(usize::MAX), pos),
+        None => (usize::MAX, usize::MAX),
+    }
+}
+
+/// the lines of a run, copied into one buffer
+#[derive(Default)]
+struct Run {
+    text: Vec<u8>,
+    lines: Vec<(SortKey, Range<usize>)>,
+}
+
+impl Run {
+    fn push(&mut self, key: SortKey, line: &[u8]) {
+        let start = self.text.len();
+        self.text.extend_from_slice(line);
+        self.lines.push((key, start..self.text.len()));
+    }
+
+    fn bytes(&self) -> usize {
+        self.text.len() + self.lines.len() * size_of::<(SortKey, Range<usize>)>()
+    }
+
+    fn write(&mut self, tmp_dir: &Path, prefix: &str, index: usize) -> Result<TempFile> {
+        self.lines.sort_by_key(|(key, _)| *key);
+        let (run, mut output) = TempFile::create(tmp_dir, prefix, &format!("run{}.sam", index))?;
+        for (_, range) in &self.lines {
+            output.write_all(&self.text[range.clone()])?;
+            output.write_all(b"\n")?;
+        }
+        output.flush()?;
+        self.text.clear();
+        self.lines.clear();
+        Ok(run)
+    }
+}
+
+/// sorts the SAM file `input` ('-' for standard input) by coordinate into a
+/// new file in `tmp_dir`: runs of about `run_bytes` are sorted in memory and
+/// spilled to `tmp_dir`, then merged. records with equal coordinates keep
+/// their input order. the runs, and the sorted file on failure, are removed.
+pub fn external_sort(input: &Path, tmp_dir: &Path, run_bytes: usize) -> Result<TempFile> {
+    let prefix = temp_prefix();
+
+    let mut ranks: AHashMap<Vec<u8>, usize> = AHashMap::new();
+    let mut header = Vec::new();
+    let mut runs = Vec::new();
+    let mut run = Run::default();
+    for line in open_input(input)?.split(b'\n') {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with(b"@") {
+            if line.starts_with(b"@SQ\t") && let Some(name) = line.split(|&b| b == b'\t').find_map(|f| f.strip_prefix(b"SN:")) {
+                let next = ranks.len();
+                ranks.entry(name.to_vec()).or_insert(next);
+            }
+            header.push(line);
+            continue;
+        }
+        if let Some((dna, _)) = record_key(&line) && dna != b"*" && !ranks.contains_key(dna) {
+            ranks.insert(dna.to_vec(), ranks.len());
+        }
+        run.push(sort_key(&ranks, &line), &line);
+        if run.bytes() >= run_bytes {
+            runs.push(run.write(tmp_dir, &prefix, runs.len())?);
+        }
+    }
+    if !run.lines.is_empty() {
+        runs.push(run.write(tmp_dir, &prefix, runs.len())?);
+    }
+    drop(run);
+
+    let (sorted, mut output) = TempFile::create(tmp_dir, &prefix, "sorted.sam")?;
+    for line in header {
+        output.write_all(&line)?;
+        output.write_all(b"\n")?;
+    }
+    let mut readers = runs
+        .iter()
+        .map(|run| Ok(BufReader::new(File::open(run.path())?).split(b'\n')))
+        .collect::<Result<Vec<_>>>()?;
+    // ties are broken by run index, which keeps the input order
+    let mut heap = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some(line) = reader.next() {
+            let line = line?;
+            heap.push(Reverse((sort_key(&ranks, &line), i, line)));
+        }
+    }
+    while let Some(Reverse((_, i, line))) = heap.pop() {
+        output.write_all(&line)?;
+        output.write_all(b"\n")?;
+        if let Some(line) = readers[i].next() {
+            let line = line?;
+            heap.push(Reverse((sort_key(&ranks, &line), i, line)));
+        }
+    }
+    output.flush()?;
+    Ok(sorted)
+}
+
+#[test]
+fn test_external_sort() {
+    let dir = std::env::temp_dir().join(temp_prefix());
+    fs::create_dir(&dir).unwrap();
+    let input = dir.join("input.sam");
+    fs::write(&input, "@SQ\tSN:chr2\n@SQ\tSN:chr1\nr1\t0\tchr1\t5\nr2\t0\t*\t0\nr3\t0\tchr2\t9\nr4\t0\tchr1\t2\nr5\t0\tchr1\t5\n").unwrap();
+    let sorted = external_sort(&input, &dir, 1).unwrap();
+    assert_eq!(fs::read_to_string(sorted.path()).unwrap(),
+        "@SQ\tSN:chr2\n@SQ\tSN:chr1\nr3\t0\tchr2\t9\nr4\t0\tchr1\t2\nr1\t0\tchr1\t5\nr5\t0\tchr1\t5\nr2\t0\t*\t0\n");
+    // the runs are gone, and the sorted file once dropped
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    drop(sorted);
+    fs::remove_file(&input).unwrap();
+    fs::remove_dir(&dir).unwrap();
+}
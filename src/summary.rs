@@ -0,0 +1,437 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use ahash::AHashMap;
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::context::Context;
+use crate::output::base_change_label;
+use crate::position::Position;
+use crate::pvalue::TestCount;
+use crate::ARGS;
+
+/// counters of a task, merged in `main`
+#[derive(Default)]
+pub struct Stats {
+    /// alignment records parsed
+    pub records: usize,
+    pub mapped: usize,
+    /// mapped reads on dnas or outside the regions not selected, e.g. by
+    /// `--region` or missing from the reference
+    pub unselected: usize,
+    /// reads skipped by `--unique-only` or `--multiple-only`
+    pub uniqueness_filtered: usize,
+    /// reads without a value of the `--group-tag`
+    pub ungrouped: usize,
+    /// reads without a value of the `--umi-tag`
+    pub no_umi: usize,
+    /// reads without a converted strand
+    pub no_strand: usize,
+    /// reads from a strand not sequenced in the `--library-type`
+    pub library_filtered: usize,
+    /// reads dropped by the off-target conversion filter
+    pub offtarget_filtered: usize,
+    /// conflicting conversion status of one read at a position
+    pub conflicts: usize,
+    /// read name hash collisions, with `--read-identity name`
+    pub collisions: usize,
+    /// aligned bases on N or IUPAC ambiguity codes of the reference
+    pub ambiguous_bases: usize,
+    /// positions with at least one counted base
+    pub covered_positions: usize,
+    /// [converted, unconverted] counted bases by (conversion, context, strand)
+    pub bases: AHashMap<(u8, Option<Context>, u8), [u64; 2]>,
+}
+
+impl Stats {
+    pub fn add_position(&mut self, p: &Position) {
+        if p.converted.is_empty() && p.unconverted.is_empty() {
+            return;
+        }
+        self.covered_positions += 1;
+        let counts = self.bases.entry((p.conversion, p.context, p.strand.unwrap_or(b'?'))).or_default();
+        counts[0] += u64::from(p.converted.count);
+        counts[1] += u64::from(p.unconverted.count);
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        self.records += other.records;
+        self.mapped += other.mapped;
+        self.unselected += other.unselected;
+        self.uniqueness_filtered += other.uniqueness_filtered;
+        self.ungrouped += other.ungrouped;
+        self.no_umi += other.no_umi;
+        self.no_strand += other.no_strand;
+        self.library_filtered += other.library_filtered;
+        self.offtarget_filtered += other.offtarget_filtered;
+        self.conflicts += other.conflicts;
+        self.collisions += other.collisions;
+        self.ambiguous_bases += other.ambiguous_bases;
+        self.covered_positions += other.covered_positions;
+        for (key, [converted, unconverted]) in &other.bases {
+            let counts = self.bases.entry(*key).or_default();
+            counts[0] += converted;
+            counts[1] += unconverted;
+        }
+    }
+
+    fn totals(&self) -> Totals {
+        self.bases.values().fold(Totals::default(), |t, c| t.add(c))
+    }
+}
+
+#[derive(Default, Serialize, Clone, Copy)]
+struct Totals {
+    converted: u64,
+    unconverted: u64,
+    conversion_rate: Option<f64>,
+}
+
+impl Totals {
+    fn add(mut self, [converted, unconverted]: &[u64; 2]) -> Self {
+        self.converted += converted;
+        self.unconverted += unconverted;
+        let total = self.converted + self.unconverted;
+        self.conversion_rate = (total > 0).then(|| self.converted as f64 / total as f64);
+        self
+    }
+}
+
+#[derive(Serialize)]
+struct Chromosome {
+    name: String,
+    length: usize,
+    covered_positions: usize,
+    counted_bases: u64,
+    /// mean depth over the covered positions
+    mean_depth: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Filtered {
+    unmapped: usize,
+    unselected: usize,
+    uniqueness: usize,
+    no_group: usize,
+    no_umi: usize,
+    no_strand: usize,
+    library_type: usize,
+    offtarget_conversions: usize,
+}
+
+/// conversion and non-conversion rates with their 95% Wilson score
+/// intervals
+#[derive(Serialize)]
+pub struct Rate {
+    pub converted: u64,
+    pub unconverted: u64,
+    pub conversion_rate: Option<f64>,
+    pub conversion_rate_ci: Option<[f64; 2]>,
+    pub non_conversion_rate: Option<f64>,
+    pub non_conversion_rate_ci: Option<[f64; 2]>,
+}
+
+impl Rate {
+    fn new(converted: u64, unconverted: u64) -> Self {
+        let n = (converted + unconverted) as f64;
+        let (rate, ci) = if n > 0.0 {
+            const Z: f64 = 1.96;
+            let p = converted as f64 / n;
+            let center = (p + Z * Z / (2.0 * n)) / (1.0 + Z * Z / n);
+            let half = Z * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt() / (1.0 + Z * Z / n);
+            (Some(p), Some([(center - half).max(0.0), (center + half).min(1.0)]))
+        } else {
+            (None, None)
+        };
+        Self {
+            converted,
+            unconverted,
+            conversion_rate: rate,
+            conversion_rate_ci: ci,
+            non_conversion_rate: rate.map(|p| 1.0 - p),
+            non_conversion_rate_ci: ci.map(|[lower, upper]| [1.0 - upper, 1.0 - lower]),
+        }
+    }
+}
+
+/// a spike-in control contig, `--unconverted-control` (e.g. lambda) or
+/// `--methylated-control` (e.g. pUC19)
+#[derive(Serialize)]
+pub struct Control {
+    pub name: String,
+    pub methylated: bool,
+    pub total: Rate,
+    pub contexts: BTreeMap<&'static str, Rate>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    /// alignment records, also of the dnas and regions not selected
+    records: usize,
+    mapped: usize,
+    filtered_reads: Filtered,
+    conflicting_bases: usize,
+    read_name_hash_collisions: usize,
+    ambiguous_reference_bases: usize,
+    counted_bases: Totals,
+    contexts: BTreeMap<&'static str, Totals>,
+    strands: BTreeMap<String, Totals>,
+    base_changes: BTreeMap<String, Totals>,
+    chromosomes: Vec<Chromosome>,
+    controls: Vec<Control>,
+    threads: usize,
+    seconds: f64,
+}
+
+/// the run totals for `--summary`, with per chromosome coverage
+#[derive(Default)]
+pub struct Summary {
+    stats: Stats,
+    chromosomes: Vec<(&'static [u8], usize, Stats)>,
+}
+
+impl Summary {
+    /// merges the stats of a task on `dna`, of reference length `length`
+    pub fn add(&mut self, dna: &'static [u8], length: usize, stats: &Stats) {
+        self.stats.merge(stats);
+        match self.chromosomes.last_mut() {
+            Some((name, _, chromosome)) if *name == dna => chromosome.merge(stats),
+            _ => {
+                let mut chromosome = Stats::default();
+                chromosome.merge(stats);
+                self.chromosomes.push((dna, length, chromosome));
+            }
+        }
+    }
+
+    /// merges the stats of records not counted, of no chromosome
+    pub fn add_skipped(&mut self, stats: &Stats) {
+        self.stats.merge(stats);
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// the rates of the spike-in control contigs, in argument order
+    pub fn controls(&self) -> Vec<Control> {
+        self.controls_of(&ARGS.unconverted_control, &ARGS.methylated_control)
+    }
+
+    fn controls_of(&self, unconverted: &[String], methylated: &[String]) -> Vec<Control> {
+        let unconverted = unconverted.iter().map(|name| (name, false));
+        let methylated = methylated.iter().map(|name| (name, true));
+        unconverted
+            .chain(methylated)
+            .map(|(name, methylated)| {
+                let stats = self.chromosomes.iter().find(|(dna, ..)| *dna == name.as_bytes()).map(|(_, _, s)| s);
+                let mut contexts: BTreeMap<&str, [u64; 2]> = BTreeMap::new();
+                for (&(_, context, _), [converted, unconverted]) in stats.iter().flat_map(|s| &s.bases) {
+                    let counts = contexts.entry(context.map_or(".", Context::as_str)).or_default();
+                    counts[0] += converted;
+                    counts[1] += unconverted;
+                }
+                let total = contexts.values().fold([0, 0], |t, c| [t[0] + c[0], t[1] + c[1]]);
+                Control {
+                    name: name.clone(),
+                    methylated,
+                    total: Rate::new(total[0], total[1]),
+                    contexts: contexts.into_iter().map(|(c, [converted, unconverted])| (c, Rate::new(converted, unconverted))).collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// the rate of the `--test-count` on the controls where it is not
+    /// expected: converted bases of the methylated controls or unconverted
+    /// bases of the unconverted ones, with half a pseudo-count on either
+    /// side. None without counted control bases.
+    pub fn control_error_rate(&self) -> Option<f64> {
+        error_rate(&self.controls(), ARGS.test_count == TestCount::Converted)
+    }
+
+    fn report(&self, elapsed: Duration) -> Report {
+        let s = &self.stats;
+        let mut contexts = BTreeMap::new();
+        let mut strands = BTreeMap::new();
+        let mut base_changes = BTreeMap::new();
+        let add = |totals: &mut Totals, counts| *totals = totals.add(counts);
+        for (&(conversion, context, strand), counts) in &s.bases {
+            add(contexts.entry(context.map_or(".", Context::as_str)).or_default(), counts);
+            add(strands.entry(char::from(strand).to_string()).or_default(), counts);
+            add(base_changes.entry(base_change_label(conversion)).or_default(), counts);
+        }
+        let chromosomes = self.chromosomes
+            .iter()
+            .map(|(name, length, c)| {
+                let totals = c.totals();
+                let counted_bases = totals.converted + totals.unconverted;
+                Chromosome {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    length: *length,
+                    covered_positions: c.covered_positions,
+                    counted_bases,
+                    mean_depth: (c.covered_positions > 0).then(|| counted_bases as f64 / c.covered_positions as f64),
+                }
+            })
+            .collect();
+        Report {
+            records: s.records,
+            mapped: s.mapped,
+            filtered_reads: Filtered {
+                unmapped: s.records - s.mapped,
+                unselected: s.unselected,
+                uniqueness: s.uniqueness_filtered,
+                no_group: s.ungrouped,
+                no_umi: s.no_umi,
+                no_strand: s.no_strand,
+                library_type: s.library_filtered,
+                offtarget_conversions: s.offtarget_filtered,
+            },
+            conflicting_bases: s.conflicts,
+            read_name_hash_collisions: s.collisions,
+            ambiguous_reference_bases: s.ambiguous_bases,
+            counted_bases: s.totals(),
+            contexts,
+            strands,
+            base_changes,
+            chromosomes,
+            controls: self.controls(),
+            threads: ARGS.threads,
+            seconds: elapsed.as_secs_f64(),
+        }
+    }
+
+    /// writes JSON, or `key<TAB>value` lines with dotted keys for a .tsv
+    /// `path`
+    pub fn write(&self, path: &Path, elapsed: Duration) -> Result<()> {
+        let report = self.report(elapsed);
+        let mut output = BufWriter::new(File::create(path)?);
+        if path.extension().is_some_and(|e| e == "tsv") {
+            let mut rows = Vec::new();
+            flatten(String::new(), &serde_json::to_value(&report)?, &mut rows);
+            for (key, value) in rows {
+                writeln!(output, "{}\t{}", key, value)?;
+            }
+        } else {
+            serde_json::to_writer_pretty(&mut output, &report)?;
+            writeln!(output)?;
+        }
+        output.flush()?;
+        Ok(())
+    }
+}
+
+/// the rate of converted bases on the methylated `controls` if `converted`,
+/// else of unconverted bases on the unconverted ones, see
+/// `Summary::control_error_rate`
+fn error_rate(controls: &[Control], converted: bool) -> Option<f64> {
+    let (tested, total) = controls
+        .iter()
+        .filter(|c| c.methylated == converted)
+        .fold((0, 0), |(tested, total), c| {
+            let count = if converted { c.total.converted } else { c.total.unconverted };
+            (tested + count, total + c.total.converted + c.total.unconverted)
+        });
+    (total > 0).then(|| (tested as f64 + 0.5) / (total as f64 + 1.0))
+}
+
+/// flattens `value` into (dotted key, value) rows; array items are keyed by
+/// their `name`, or their index
+fn flatten(prefix: String, value: &Value, rows: &mut Vec<(String, String)>) {
+    let key = |k: &str| if prefix.is_empty() { k.to_owned() } else { format!("{}.{}", prefix, k) };
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                if k != "name" {
+                    flatten(key(k), v, rows);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter().enumerate() {
+                let k = v.get("name").and_then(Value::as_str).map_or_else(|| i.to_string(), str::to_owned);
+                flatten(key(&k), v, rows);
+            }
+        }
+        Value::Null => rows.push((prefix, ".".to_owned())),
+        Value::String(s) => rows.push((prefix, s.clone())),
+        v => rows.push((prefix, v.to_string())),
+    }
+}
+
+#[test]
+fn test_report() {
+    let mut summary = Summary::default();
+    let mut stats = Stats { records: 5, mapped: 4, no_strand: 1, covered_positions: 2, ..Default::default() };
+    stats.bases.insert((0, Some(Context::Cg), b'+'), [3, 1]);
+    summary.add(b"chr1", 100, &stats);
+    summary.add_skipped(&Stats { records: 3, mapped: 1, unselected: 1, ..Default::default() });
+    let report = serde_json::to_value(summary.report(Duration::from_secs(2))).unwrap();
+    let keys: Vec<_> = report.as_object().unwrap().keys().map(String::as_str).collect();
+    assert_eq!(keys, [
+        "records", "mapped", "filtered_reads", "conflicting_bases", "read_name_hash_collisions",
+        "ambiguous_reference_bases", "counted_bases", "contexts", "strands", "base_changes",
+        "chromosomes", "controls", "threads", "seconds",
+    ]);
+    assert_eq!((report["records"].as_u64(), report["mapped"].as_u64()), (Some(8), Some(5)));
+    let filtered = &report["filtered_reads"];
+    assert_eq!((filtered["unmapped"].as_u64(), filtered["unselected"].as_u64(), filtered["no_strand"].as_u64()), (Some(3), Some(1), Some(1)));
+    assert_eq!(report["counted_bases"]["conversion_rate"].as_f64(), Some(0.75));
+    assert_eq!(report["contexts"]["CG"]["converted"].as_u64(), Some(3));
+    assert_eq!(report["strands"]["+"]["unconverted"].as_u64(), Some(1));
+    assert_eq!(report["base_changes"]["C>T"]["converted"].as_u64(), Some(3));
+    let chromosome = &report["chromosomes"][0];
+    assert_eq!((chromosome["name"].as_str(), chromosome["length"].as_u64()), (Some("chr1"), Some(100)));
+    assert_eq!((chromosome["counted_bases"].as_u64(), chromosome["mean_depth"].as_f64()), (Some(4), Some(2.0)));
+    assert_eq!(report["controls"].as_array().map(Vec::len), Some(0));
+    assert_eq!(report["seconds"].as_f64(), Some(2.0));
+}
+
+#[test]
+fn test_wilson_rate() {
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-4;
+    let rate = Rate::new(8, 2);
+    let [lower, upper] = rate.conversion_rate_ci.unwrap();
+    assert_eq!(rate.conversion_rate, Some(0.8));
+    assert!(close(lower, 0.4902) && close(upper, 0.9433), "{} {}", lower, upper);
+    let [non_lower, non_upper] = rate.non_conversion_rate_ci.unwrap();
+    assert!(close(non_lower, 1.0 - upper) && close(non_upper, 1.0 - lower));
+    assert!(close(rate.non_conversion_rate.unwrap(), 0.2));
+
+    // clamped to [0, 1] at the edges
+    let [lower, upper] = Rate::new(0, 5).conversion_rate_ci.unwrap();
+    assert!(lower == 0.0 && close(upper, 0.4345), "{}", upper);
+    assert_eq!(Rate::new(5, 0).conversion_rate_ci.unwrap()[1], 1.0);
+
+    let empty = Rate::new(0, 0);
+    assert!(empty.conversion_rate.is_none() && empty.conversion_rate_ci.is_none() && empty.non_conversion_rate.is_none());
+}
+
+#[test]
+fn test_control_error_rate() {
+    let mut summary = Summary::default();
+    for (dna, counts) in [(&b"lambda"[..], [[990, 6], [1000, 4]]), (b"chr1", [[50, 50], [0, 0]]), (b"puc19", [[3, 97], [0, 0]])] {
+        let mut stats = Stats::default();
+        stats.bases.insert((0, Some(Context::Cg), b'+'), counts[0]);
+        stats.bases.insert((0, Some(Context::Chh), b'-'), counts[1]);
+        summary.add(dna, 1000, &stats);
+    }
+    let controls = summary.controls_of(&["lambda".to_owned()], &["puc19".to_owned(), "missing".to_owned()]);
+    assert_eq!(controls.iter().map(|c| (c.name.as_str(), c.methylated)).collect::<Vec<_>>(),
+        [("lambda", false), ("puc19", true), ("missing", true)]);
+    assert_eq!((controls[0].total.converted, controls[0].total.unconverted), (1990, 10));
+    assert_eq!(controls[0].contexts["CG"].converted, 990);
+    assert!(controls[2].total.conversion_rate.is_none());
+
+    // unconverted bases of lambda, converted ones of pUC19, with half a
+    // pseudo-count
+    assert_eq!(error_rate(&controls, false), Some(10.5 / 2001.0));
+    assert_eq!(error_rate(&controls, true), Some(3.5 / 101.0));
+    assert_eq!(error_rate(&controls[..1], true), None);
+}
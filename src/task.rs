@@ -1,33 +1,257 @@
 // we use term dna instead of chromosome in this module
 
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::hint::cold_path;
 use std::ops::Range;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Condvar, Mutex};
+
+use ahash::AHashSet;
+use anyhow::{bail, Result};
 
 use crate::alignment::Alignment;
-use crate::DNAS;
+use crate::mbias::MBias;
+use crate::summary::Stats;
+use crate::utils::{ChunkIterator, CigarIterator};
 use crate::{
-    position::Position,
+    position::{Position, ReadIdTable},
     ARGS,
 };
 
+#[derive(Clone)]
 pub struct Task2<'a> {
     pub dna_name: &'a [u8],
-    pub alignments: Vec<Alignment<'a>>,
+    /// the alignment lines of the task in each `--alignments` input, parsed
+    /// by the worker
+    pub lines: Vec<&'a [u8]>,
+    /// total read length of the lines
+    pub bases: usize,
     pub position_range: Range<usize>,
 }
 
-pub type TaskResult<'a> = Option<Vec<Position<'a>>>;
+impl<'a> Task2<'a> {
+    /// the alignments of all inputs in coordinate order. with several
+    /// inputs, the sample of an alignment is the index of its input.
+    pub fn alignments(&self) -> impl Iterator<Item = Alignment<'a>> {
+        let samples = self.lines.len() > 1;
+        MergedLines::new(&self.lines).filter_map(move |(i, line)| {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            let mut alignment = Alignment::from_file(line).ok()?;
+            if samples {
+                alignment.sample = i as u32;
+            }
+            Some(alignment)
+        })
+    }
+}
+
+/// the lines (with their line feed) of several coordinate sorted sources in
+/// merged order, with the index of their source; ties are broken by that
+/// index. lines other than records come right before the next record of
+/// their source.
+pub struct MergedLines<'a> {
+    /// the lines of each source not read yet
+    sources: Vec<&'a [u8]>,
+    /// the next line of each source
+    heads: Vec<&'a [u8]>,
+    /// (location, source) of the heads
+    heap: BinaryHeap<Reverse<(usize, usize)>>,
+}
+
+impl<'a> MergedLines<'a> {
+    pub fn new(srcs: &[&'a [u8]]) -> Self {
+        let mut lines = Self {
+            sources: srcs.to_vec(),
+            heads: vec![&[][..]; srcs.len()],
+            heap: BinaryHeap::with_capacity(srcs.len()),
+        };
+        for i in 0..srcs.len() {
+            lines.advance(i);
+        }
+        lines
+    }
+
+    /// takes the next line of source `i` as its head
+    fn advance(&mut self, i: usize) {
+        let rest = self.sources[i];
+        if rest.is_empty() {
+            return;
+        }
+        let (line, rest) = rest.split_at(memchr::memchr(b'\n', rest).map_or(rest.len(), |end| end + 1));
+        self.sources[i] = rest;
+        self.heads[i] = line;
+        // a single source keeps its order without looking at the lines
+        let location = if self.sources.len() > 1 {
+            record_key(line.strip_suffix(b"\n").unwrap_or(line)).map_or(0, |(_, pos)| pos)
+        } else {
+            0
+        };
+        self.heap.push(Reverse((location, i)));
+    }
+}
+
+impl<'a> Iterator for MergedLines<'a> {
+    type Item = (usize, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, i)) = self.heap.pop()?;
+        let line = self.heads[i];
+        self.advance(i);
+        Some((i, line))
+    }
+}
+
+pub struct TaskOutput<'a> {
+    pub dna: &'a [u8],
+    pub positions: Vec<Position<'a>>,
+    pub mbias: MBias,
+    pub stats: Stats,
+}
+
+/// records sampled for the mean read length
+const SAMPLED_RECORDS: usize = 10000;
+
+/// memory of a task per alignment line of `read_len` bases: the read id
+/// entry and the kept quality of each base, and the interned read name
+fn alignment_bytes(read_len: usize) -> usize {
+    let quality = usize::from(!ARGS.counts_only);
+    read_len * (ReadIdTable::ENTRY_BYTES + quality) + size_of::<((u32, &[u8]), u64)>() + size_of::<(u32, u64)>()
+}
+
+/// memory of a task per reference position, at most: a dense `Position`
+/// for each base change and sample, if every sample has reads in the task
+fn position_bytes(samples: usize) -> usize {
+    size_of::<Position>() * ARGS.base_change.len() * samples
+}
+
+/// mean read length of the first `SAMPLED_RECORDS` records of `src`
+fn mean_read_length(src: &[u8]) -> usize {
+    let (n, bases) = src
+        .split(|&b| b == b'\n')
+        .filter_map(scan_line)
+        .take(SAMPLED_RECORDS)
+        .fold((0, 0), |(n, bases), (_, _, _, read_len)| (n + 1, bases + read_len));
+    bases.checked_div(n).unwrap_or(0)
+}
+
+/// number of tasks allowed in flight, between issued and written
+pub fn task_window() -> usize {
+    2 * ARGS.threads.max(1)
+}
+
+/// (alignment lines, reference positions) per task. given `--max-memory`
+/// the block sizes are derived from the share of each task in flight and
+/// the read length measured on the records of `src`.
+pub fn block_sizes(src: &[u8], samples: usize) -> (usize, usize) {
+    const DEFAULT_BLOCK_SIZE: usize = 20000000;
+    let task_budget = ARGS.max_memory.map(|m| m / task_window());
+    let align = ARGS.align_block_size
+        .or(task_budget.map(|b| b / alignment_bytes(mean_read_length(src))))
+        .unwrap_or(DEFAULT_BLOCK_SIZE);
+    let reference = ARGS.ref_block_size
+        .or(task_budget.map(|b| b / position_bytes(samples)))
+        .unwrap_or(DEFAULT_BLOCK_SIZE);
+    (align.max(1), reference.max(1))
+}
+
+/// counts the tasks in flight, so that a slow writer holds back new tasks
+/// instead of buffering their results without bound.
+pub struct TaskWindow {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl TaskWindow {
+    pub fn new(size: usize) -> Self {
+        Self { free: Mutex::new(size), freed: Condvar::new() }
+    }
+
+    pub fn acquire(&self) {
+        let mut free = self.freed.wait_while(self.free.lock().unwrap(), |free| *free == 0).unwrap();
+        *free -= 1;
+    }
+
+    pub fn release(&self) {
+        *self.free.lock().unwrap() += 1;
+        self.freed.notify_one();
+    }
+}
+
+/// runs `work` on the thread pool for each task in order and sends the
+/// results with their sequence numbers to `tx`. a task is only handed to the
+/// pool once `window` has room for it, so no pool thread waits on the window.
+pub fn issue_tasks<T, R>(tasks: impl Iterator<Item = T>, window: &TaskWindow, tx: SyncSender<(usize, R)>,
+                         work: impl Fn(T) -> R + Send + Copy + 'static)
+where
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    for (i, task) in tasks.enumerate() {
+        window.acquire();
+        let tx = tx.clone();
+        rayon::spawn(move || {
+            // the receiver only hangs up when writing failed
+            let _ = tx.send((i, work(task)));
+        });
+    }
+}
+
+/// reference span of an alignment, at least as long as the positions its
+/// bases may reach
+fn reference_span(cigar: &[u8], seq_len: usize) -> usize {
+    seq_len + CigarIterator::new(cigar)
+        .filter(|(_, symbol)| matches!(symbol, b'D' | b'N'))
+        .map(|(len, _)| len)
+        .sum::<usize>()
+}
+
+/// (dna, location, reference span, read length) of an alignment line,
+/// without parsing the whole record
+fn scan_line(line: &[u8]) -> Option<(&[u8], usize, usize, usize)> {
+    if line.first().is_none_or(|&b| b == b'@') {
+        return None;
+    }
+    let mut fields = ChunkIterator::new(line, memchr::memchr_iter(b'\t', line));
+    let dna = fields.nth(2)?;
+    let pos = atoi_simd::parse(fields.next()?).ok()?;
+    let cigar = fields.nth(1)?;
+    let sequence = fields.nth(3)?;
+    Some((dna, pos, reference_span(cigar, sequence.len()), sequence.len()))
+}
 
+/// splits the alignments of one dna into tasks. a task is only cut before an
+/// alignment starting after `position_range.end`, the location past the last
+/// base any earlier alignment may reach. so no read of a task reaches the
+/// range of another one, and the location at `position_range.end` lies in
+/// no task at all: there is at least one uncovered base between the ranges
+/// of consecutive tasks, which `merge_cpg_strands` relies on. with several
+/// inputs, the alignments are walked in merged order and every input is
+/// cut at the same place.
 pub struct TaskIter2<'a> {
-    src: &'a [u8],
-    current_position: usize,
+    srcs: Vec<&'a [u8]>,
+    lines: std::iter::Peekable<MergedLines<'a>>,
+    /// the start of the next task in each source
+    starts: Vec<usize>,
+    /// the end of the lines taken from each source
+    ends: Vec<usize>,
+    /// the end of the lines read from each source
+    consumed: Vec<usize>,
+    align_block_size: usize,
+    ref_block_size: usize,
 }
 
 impl<'a> TaskIter2<'a> {
-    pub fn new(src: &'a [u8]) -> Self {
+    /// the tasks of the alignments of one dna in each input
+    pub fn new(srcs: Vec<&'a [u8]>, (align_block_size, ref_block_size): (usize, usize)) -> Self {
         Self {
-            src,
-            current_position: 0,
+            lines: MergedLines::new(&srcs).peekable(),
+            starts: vec![0; srcs.len()],
+            ends: vec![0; srcs.len()],
+            consumed: vec![0; srcs.len()],
+            srcs,
+            align_block_size,
+            ref_block_size,
         }
     }
 }
@@ -37,43 +261,34 @@ impl<'a> Iterator for TaskIter2<'a> {
 
     #[inline(never)]
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current_position >= self.src.len() {
-            return None;
-        }
-        let chunk_start = self.current_position;
-        let lines = memchr::memchr_iter(b'\n', &self.src[chunk_start..]);
-        let mut line_start = chunk_start;
-        let mut current_dna_name = &self.src[0..0];
+        self.lines.peek()?;
+        let mut current_dna_name = &b""[..];
         let mut current_chunk_beginning_pos = usize::MAX;
         let mut current_chunk_end_pos = usize::MAX;
         let mut n = 0;
-        let mut chunk_end: usize = 0;
-        let mut alignments: Vec<Alignment<'a>> = Vec::new();
-        for line_feed_pos in lines {
-            let actual_feed_pos = chunk_start + line_feed_pos;
-            let line = &self.src[line_start..actual_feed_pos];
-            line_start = actual_feed_pos + 1;
-            let alignment = match Alignment::from_file(line) {
-                Ok(alignment) => alignment,
-                Err(_) => continue,
+        let mut bases = 0;
+        // the last line may lack its line feed
+        while let Some(&(i, line)) = self.lines.peek() {
+            let Some((dna, pos, seq_len, read_len)) = scan_line(line.strip_suffix(b"\n").unwrap_or(line)) else {
+                self.lines.next();
+                self.consumed[i] += line.len();
+                continue;
             };
-            let seq_len: usize = alignment.bases.iter().map(|it| it.ref_pos).max().unwrap_or(alignment.sequence.len() as isize).try_into().unwrap();
-            let pos = alignment.location as usize;
 
             if current_dna_name.len() == 0 {
                 cold_path();
-                current_dna_name = alignment.dna;
-            } else if current_dna_name != alignment.dna {
+                current_dna_name = dna;
+            } else if current_dna_name != dna {
                 break; // 更换 ref 文件，放回当前行
             }
             if current_chunk_beginning_pos == usize::MAX {
                 cold_path();
                 current_chunk_beginning_pos = pos;
                 current_chunk_end_pos = pos + seq_len + 1;
-            } else if pos - current_chunk_beginning_pos > ARGS.ref_block_size && pos > current_chunk_end_pos {
+            } else if pos - current_chunk_beginning_pos > self.ref_block_size && pos > current_chunk_end_pos {
                 break; // 当前 chunk 过大，放回当前行
             }
-            if n >= ARGS.align_block_size && pos > current_chunk_end_pos {
+            if n >= self.align_block_size && pos > current_chunk_end_pos {
                 break; // // 当前 chunk 过大，放回当前行
                 // 注意必须保证各个段之间即使算上 location ~bases~ 延申之后还没有任何重叠！
                 // 并且还不能紧密连接，因此这里是大于不是大于等于，因为下一个碱基可能影响上一个的 strand
@@ -81,49 +296,276 @@ impl<'a> Iterator for TaskIter2<'a> {
             current_chunk_end_pos = std::cmp::max(current_chunk_end_pos, pos + seq_len + 1); // 因此如果还在重叠区间内就不能分割
 
             n += 1;
-            chunk_end = line_start;
-            alignments.push(alignment);
-            if line_start >= self.src.len() {
-                break;
-            }
+            self.lines.next();
+            self.consumed[i] += line.len();
+            self.ends[i] = self.consumed[i];
+            bases += read_len;
         }
-        self.current_position = chunk_end;
         if current_dna_name.len() == 0 || current_chunk_beginning_pos == usize::MAX {
             None
         } else {
             // eprintln!("fn {} position range {} - {}, size {}", str::from_utf8(&current_dna_name).unwrap(), current_chunk_beginning_pos, current_chunk_end_pos, current_chunk_end_pos - current_chunk_beginning_pos);
+            // lines after the last record of a source go to the next task
+            let lines = self.srcs.iter().zip(&self.starts).zip(&self.ends).map(|((src, &start), &end)| &src[start..end]).collect();
+            self.starts.clone_from(&self.ends);
             Some(Task2 {
                 dna_name: current_dna_name,
-                alignments,
+                lines,
+                bases,
                 position_range: current_chunk_beginning_pos .. current_chunk_end_pos,
             })
         }
     }
 }
 
-pub fn scan_alignment_segments<'a>(src: &'a [u8]) -> Vec<(&'a [u8], Range<usize>)> {
-    let iter = memchr::memchr_iter(b'\n', src);
+/// the records and mapped records of alignment lines that are not counted,
+/// on a dna or outside the regions not selected
+pub fn skipped_records(lines: &[u8]) -> Stats {
+    let mut stats = Stats::default();
+    for line in lines.split(|&b| b == b'\n') {
+        if line.first().is_none_or(|&b| b == b'@') {
+            continue;
+        }
+        let flag = ChunkIterator::new(line, memchr::memchr_iter(b'\t', line)).nth(1);
+        let Some(flag) = flag.and_then(|f| atoi_simd::parse::<i32>(f).ok()) else {
+            continue;
+        };
+        stats.records += 1;
+        if flag & 4 == 0 {
+            stats.mapped += 1;
+            stats.unselected += 1;
+        }
+    }
+    stats
+}
+
+/// (dna, location) of an alignment line
+pub fn record_key(line: &[u8]) -> Option<(&[u8], usize)> {
+    let mut fields = ChunkIterator::new(line, memchr::memchr_iter(b'\t', line));
+    let dna = fields.nth(2)?;
+    let pos = atoi_simd::parse(fields.next()?).ok()?;
+    Some((dna, pos))
+}
+
+/// the values of an aux field (e.g. b"RG") in order of first appearance:
+/// for RG the `@RG` IDs of the header, then the values of the records
+pub struct AuxValues<'a> {
+    tag: &'a [u8],
+    seen: AHashSet<&'a [u8]>,
+    pub values: Vec<&'a [u8]>,
+}
+
+impl<'a> AuxValues<'a> {
+    pub fn new(tag: &'a [u8]) -> Self {
+        Self { tag, seen: AHashSet::new(), values: Vec::new() }
+    }
+
+    fn add(&mut self, line: &'a [u8]) {
+        let value = if line.starts_with(b"@") {
+            if self.tag != b"RG" || !line.starts_with(b"@RG\t") {
+                return;
+            }
+            line.split(|&b| b == b'\t').find_map(|f| f.strip_prefix(b"ID:"))
+        } else {
+            aux_value(line, self.tag)
+        };
+        if let Some(value) = value && self.seen.insert(value) {
+            self.values.push(value);
+        }
+    }
+}
+
+/// splits `src` into the (dna, byte range) blocks of consecutive alignments
+/// on the same dna, collecting the aux `values` on the way. the header is
+/// not part of any block. fails on the first line breaking coordinate order:
+/// a smaller location than the line before, or a dna coming back after
+/// another one.
+pub fn scan_alignment_segments<'a>(src: &'a [u8], mut values: Option<&mut AuxValues<'a>>) -> Result<Vec<(&'a [u8], Range<usize>)>> {
     let mut current_name: &'a [u8] = &src[0..0];
+    let mut current_pos = 0;
+    let mut seen_names = AHashSet::new();
     let mut chunk_start = 0;
     let mut line_start = 0;
+    let mut line_number = 0;
     let mut result: Vec<(&'a [u8], Range<usize>)> = Vec::new();
-    for line_end in iter {
-        if src[line_start] == b'@' { 
-            line_start = line_end + 1;
-            continue 
+    while line_start < src.len() {
+        let line_end = memchr::memchr(b'\n', &src[line_start..]).map_or(src.len(), |i| line_start + i);
+        let line = &src[line_start..line_end];
+        line_number += 1;
+        if let Some(values) = &mut values {
+            values.add(line);
         }
-        let mut t = memchr::memchr_iter(b'\t', &src[line_start..line_end]);
-        if let (Some(a), Some(b)) = (t.nth(1), t.next()) {
-            let name = &src[(line_start + a + 1)..(line_start + b)];
+        if !line.starts_with(b"@") && let Some((name, pos)) = record_key(line) {
             if name != current_name {
-                if !current_name.is_empty() && DNAS.contains_key(current_name) {
+                if !current_name.is_empty() {
                     result.push((current_name, chunk_start..line_start));
                 }
+                if name != b"*" && !seen_names.insert(name) {
+                    bail!("alignments of {} are not contiguous, see line {}: {}\nplease sort the input by coordinate or use --sort-input",
+                        String::from_utf8_lossy(name), line_number, String::from_utf8_lossy(line));
+                }
                 current_name = name;
                 chunk_start = line_start;
+            } else if pos < current_pos && name != b"*" {
+                bail!("alignments are not sorted by coordinate, see line {}: {}\nplease sort the input by coordinate or use --sort-input",
+                    line_number, String::from_utf8_lossy(line));
             }
+            current_pos = pos;
         }
         line_start = line_end + 1;
     }
-    result
+    if !current_name.is_empty() {
+        result.push((current_name, chunk_start..src.len()));
+    }
+    Ok(result)
+}
+
+/// the value of the aux field `tag` (e.g. b"RG") of an alignment line
+pub fn aux_value<'a>(line: &'a [u8], tag: &[u8]) -> Option<&'a [u8]> {
+    ChunkIterator::new(line, memchr::memchr_iter(b'\t', line))
+        .skip(11)
+        .find(|f| f.len() >= 5 && &f[..2] == tag && f[2] == b':')
+        .map(|f| &f[5..])
+}
+
+#[cfg(test)]
+const TEST_SIZES: (usize, usize) = (20000000, 20000000);
+
+#[test]
+fn test_single_dna_segment() {
+    let sam = b"@HD\tVN:1.0\n@SQ\tSN:chrM\tLN:100\nr1\t0\tchrM\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\nr2\t0\tchrM\t3\t60\t4M\t*\t0\t0\tGTAC\tIIII\n";
+    let start = sam.windows(3).position(|w| w == b"\nr1").unwrap() + 1;
+    let segments = scan_alignment_segments(sam, None).unwrap();
+    assert_eq!(segments, vec![(&b"chrM"[..], start..sam.len())]);
+}
+
+#[test]
+fn test_last_dna_segment_without_newline() {
+    let sam = b"r1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\nr2\t0\tchrM\t3\t60\t4M\t*\t0\t0\tGTAC\tIIII";
+    let segments = scan_alignment_segments(sam, None).unwrap();
+    let names: Vec<_> = segments.iter().map(|(name, _)| *name).collect();
+    assert_eq!(names, vec![&b"chr1"[..], &b"chrM"[..]]);
+    assert_eq!(segments[1].1.end, sam.len());
+
+    let (_, range) = &segments[1];
+    let tasks: Vec<_> = TaskIter2::new(vec![&sam[range.clone()]], TEST_SIZES).collect();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].lines, [&sam[range.clone()]]);
+    assert_eq!(tasks[0].position_range, 3..(3 + 4 + 1));
+}
+
+#[test]
+fn test_single_dna_tasks() {
+    let sam = b"r1\t0\tchrM\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\nr2\t0\tchrM\t20\t60\t2M1D2M\t*\t0\t0\tGTAC\tIIII\n";
+    let tasks: Vec<_> = TaskIter2::new(vec![&sam[..]], (1, 1)).collect();
+    assert_eq!(tasks.len(), 2);
+    assert_eq!(tasks[0].dna_name, b"chrM");
+    assert_eq!(tasks[0].position_range, 1..6);
+    assert_eq!(tasks[1].position_range, 20..26);
+    assert_eq!(tasks[0].lines[0].len() + tasks[1].lines[0].len(), sam.len());
+}
+
+#[test]
+fn test_unsorted_segments() {
+    let unsorted = b"r1\t0\tchr1\t5\t60\t4M\t*\t0\t0\tACGT\tIIII\nr2\t0\tchr1\t3\t60\t4M\t*\t0\t0\tGTAC\tIIII\n";
+    assert!(scan_alignment_segments(unsorted, None).is_err());
+    let split = b"r1\t0\tchr1\t5\t60\t4M\t*\t0\t0\tACGT\tIIII\nr2\t0\tchr2\t3\t60\t4M\t*\t0\t0\tGTAC\tIIII\nr3\t0\tchr1\t9\t60\t4M\t*\t0\t0\tGTAC\tIIII\n";
+    assert!(scan_alignment_segments(split, None).is_err());
+}
+
+#[test]
+fn test_scan_aux_values() {
+    let sam = b"@RG\tID:lib2\n@RG\tID:lib1\nr1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\tRG:Z:lib1\tCB:Z:AAC\nr2\t0\tchr1\t3\t60\t4M\t*\t0\t0\tGTAC\tIIII\tRG:Z:lib3\nr3\t0\tchr1\t5\t60\t4M\t*\t0\t0\tGTAC\tIIII\tCB:Z:GGT\n";
+    let scan = |tag| {
+        let mut values = AuxValues::new(tag);
+        scan_alignment_segments(sam, Some(&mut values)).unwrap();
+        values.values
+    };
+    assert_eq!(scan(b"RG"), vec![&b"lib2"[..], b"lib1", b"lib3"]);
+    assert_eq!(scan(b"CB"), vec![&b"AAC"[..], b"GGT"]);
+}
+
+#[test]
+fn test_task_gap() {
+    let sam = b"r1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\nr2\t0\tchr1\t7\t60\t4M\t*\t0\t0\tGTAC\tIIII\nr3\t0\tchr1\t8\t60\t4M\t*\t0\t0\tGTAC\tIIII\nr4\t0\tchr1\t30\t60\t4M\t*\t0\t0\tGTAC\tIIII\n";
+    let tasks: Vec<_> = TaskIter2::new(vec![&sam[..]], (1, 1)).collect();
+    // r2 starts right after the range 1..6 of r1, r3 overlaps r2
+    let ranges: Vec<_> = tasks.iter().map(|t| t.position_range.clone()).collect();
+    assert_eq!(ranges, vec![1..6, 7..13, 30..35]);
+    for pair in tasks.windows(2) {
+        assert!(pair[1].position_range.start > pair[0].position_range.end);
+    }
+}
+
+#[test]
+fn test_task_window() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+    const WINDOW: usize = 3;
+    let window = std::sync::Arc::new(TaskWindow::new(WINDOW));
+    let (tx, rx) = std::sync::mpsc::sync_channel(WINDOW);
+    let producer_window = window.clone();
+    let producer = std::thread::spawn(move || {
+        issue_tasks(0..50, &producer_window, tx, |i: usize| {
+            STARTED.fetch_add(1, Ordering::SeqCst);
+            i
+        })
+    });
+    let mut received = Vec::new();
+    while let Ok((i, result)) = rx.recv() {
+        assert_eq!(i, result);
+        received.push(i);
+        // every task started was issued within the window
+        assert!(STARTED.load(Ordering::SeqCst) <= received.len() - 1 + WINDOW);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        window.release();
+    }
+    producer.join().unwrap();
+    received.sort_unstable();
+    assert_eq!(received, (0..50).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_tasks_cover_lines() {
+    // the last line lacks its line feed, the header and a broken line are
+    // kept in the lines of the task they precede
+    let sam = b"@HD\tVN:1.0\nr1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\nbroken\nr2\t0\tchr1\t20\t60\t4M\t*\t0\t0\tGTAC\tIIII";
+    let tasks: Vec<_> = TaskIter2::new(vec![&sam[..]], (1, 1)).collect();
+    assert_eq!(tasks.len(), 2);
+    assert_eq!([tasks[0].lines[0], tasks[1].lines[0]].concat(), sam);
+    assert!(tasks[1].lines[0].starts_with(b"broken\n"));
+    assert_eq!(tasks[1].position_range, 20..25);
+}
+
+#[test]
+fn test_skipped_records() {
+    let sam = b"@HD\tVN:1.0\nr1\t0\tchrX\t1\t60\t4M\nr2\t4\t*\t0\t0\t*\nr3\t16\tchrX\t5\t60\t4M\nbroken\n";
+    let stats = skipped_records(sam);
+    assert_eq!((stats.records, stats.mapped, stats.unselected), (3, 2, 2));
+}
+
+#[test]
+fn test_merged_tasks() {
+    // r2 of the second input reaches into the range of r1 and joins its
+    // task, both inputs are cut before r3
+    let a = b"@SQ\tSN:chr1\nr1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII\nr3\t0\tchr1\t30\t60\t4M\t*\t0\t0\tGTAC\tIIII\n";
+    let b = b"r2\t0\tchr1\t3\t60\t4M\t*\t0\t0\tGTAC\tIIII\nr4\t0\tchr1\t31\t60\t4M\t*\t0\t0\tGTAC\tIIII";
+    let tasks: Vec<_> = TaskIter2::new(vec![&a[..], &b[..]], (1, 1)).collect();
+    let ranges: Vec<_> = tasks.iter().map(|t| t.position_range.clone()).collect();
+    assert_eq!(ranges, vec![1..8, 30..36]);
+    assert_eq!([tasks[0].lines[0], tasks[1].lines[0]].concat(), a);
+    assert_eq!([tasks[0].lines[1], tasks[1].lines[1]].concat(), b);
+    assert!(tasks[1].lines[0].starts_with(b"r3\t"));
+    let reads: Vec<_> = tasks.iter().flat_map(Task2::alignments).map(|a| (a.name, a.sample)).collect();
+    assert_eq!(reads, vec![(&b"r1"[..], 0), (b"r2", 1), (b"r3", 0), (b"r4", 1)]);
+}
+
+#[test]
+fn test_merged_lines() {
+    // ties keep the order of the inputs, lines other than records come with
+    // the next record of their input
+    let a = b"r1\t0\tchr1\t5\nr2\t0\tchr1\t9\n";
+    let b = b"@HD\tVN:1.0\nr3\t0\tchr1\t2\nr4\t0\tchr1\t5";
+    let lines: Vec<_> = MergedLines::new(&[&a[..], &b[..]]).map(|(i, line)| (i, line.split(|&b| b == b'\t').next().unwrap())).collect();
+    assert_eq!(lines, vec![(1, &b"@HD"[..]), (1, b"r3"), (0, b"r1"), (1, b"r4"), (0, b"r2")]);
 }
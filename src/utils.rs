@@ -24,6 +24,29 @@ pub fn asc2dnacomp(ch: u8) -> u8 {
     }
 }
 
+/// parses a byte size with an optional K/M/G/T suffix (powers of 1024)
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&s[..s.len() - 1], 10),
+        Some(b'M') => (&s[..s.len() - 1], 20),
+        Some(b'G') => (&s[..s.len() - 1], 30),
+        Some(b'T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let n: usize = digits.parse().map_err(|_| format!("invalid size: {}", s))?;
+    n.checked_mul(1 << shift).ok_or_else(|| format!("size too large: {}", s))
+}
+
+/// parses a two character SAM aux tag, e.g. RG
+pub fn parse_aux_tag(s: &str) -> Result<String, String> {
+    if s.len() == 2 && s.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        Ok(s.to_owned())
+    } else {
+        Err(format!("invalid aux tag: {}", s))
+    }
+}
+
 pub struct ChunkIterator<'a, I: DoubleEndedIterator<Item = usize>> {
     buffer: &'a [u8],
     separator_indices: I,